@@ -0,0 +1,24 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+/// Whether the local `getfacl` can be run at all.
+pub fn available() -> bool {
+    Command::new("getfacl").arg("--version").output().is_ok_and(|o| o.status.success())
+}
+
+/// The POSIX ACL of `path` in the text form `setfacl --set-file` reads, or
+/// `None` when it has no entries beyond what the mode bits already carry.
+/// Users and groups are given by name, so they map by name on the remote.
+pub fn read(path: &Path) -> Result<Option<String>> {
+    let out = Command::new("getfacl")
+        .args(["--absolute-names", "--omit-header", "--skip-base", "--"])
+        .arg(path)
+        .output()
+        .context("Running getfacl")?;
+    if !out.status.success() {
+        bail!("getfacl {}: {}", path.display(), String::from_utf8_lossy(&out.stderr).trim());
+    }
+    let acl = String::from_utf8(out.stdout).with_context(|| format!("ACL of {} is not UTF-8", path.display()))?;
+    Ok(Some(acl).filter(|a| !a.trim().is_empty()))
+}
@@ -0,0 +1,37 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+/// What the targets are (--backend).
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backend {
+    /// Directories on SSH servers, over SFTP
+    #[default]
+    Sftp,
+    /// A bucket in an S3-compatible object store, through the `aws` CLI
+    S3,
+}
+
+/// The storage operations every backend provides. `Target` maps local paths
+/// to remote ones and builds the rest on top of these; the SFTP-only extras
+/// (modes, symlinks, --atomic, --append-mode, …) go to the SFTP session
+/// directly. Paths here are remote paths as `Target::remote_path` returns them.
+pub trait RemoteBackend {
+    /// Write the contents of `local` to `remote`, telling `progress` about
+    /// every chunk sent.
+    fn put(&self, local: &Path, remote: &Path, progress: &mut dyn FnMut(u64)) -> Result<()>;
+
+    /// Remove the file at `remote`.
+    fn delete(&self, remote: &Path) -> Result<()>;
+
+    /// Create the directory `remote`, whose parent exists. False if there was
+    /// nothing to create, as in an object store, where directories only exist
+    /// through the keys below them.
+    fn mkdir(&self, remote: &Path, mode: i32) -> Result<bool>;
+
+    /// Size and mtime of the file at `remote`, or None if there is no file.
+    fn stat(&self, remote: &Path) -> Result<Option<(u64, i64)>>;
+
+    /// Every file below the directory `remote`, at any depth, with its size.
+    fn list(&self, remote: &Path) -> Result<Vec<(PathBuf, u64)>>;
+}
@@ -0,0 +1,99 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+use tracing::info;
+
+use crate::cli::Cli;
+use crate::progress::human_bytes;
+use crate::target::Target;
+
+/// File sizes exercised by the benchmark, with how many files of each.
+const ROUNDS: [(u64, usize); 4] = [(4 << 10, 200), (256 << 10, 40), (4 << 20, 8), (64 << 20, 1)];
+
+/// Upload synthetic files of several sizes into a scratch directory below the
+/// remote dir, print the throughput for each size, then remove everything.
+/// Only the file size varies; every round runs with the connection settings
+/// given on the command line.
+pub fn run(cli: &Cli) -> Result<()> {
+    let pairs = cli.targets()?;
+    let [endpoint] = pairs.as_slice() else {
+        bail!("bench works on a single host, got {}", pairs.len());
+    };
+    let mut target = Target::connect(cli, endpoint)?;
+
+    let scratch = format!(".rmote-bench-{}", std::process::id());
+    let local = std::env::temp_dir().join(&scratch);
+    let remote = target.remote_path(Path::new(&scratch));
+    fs::create_dir_all(&local).with_context(|| format!("Creating {}", local.display()))?;
+    target.ensure_dir(Some(&remote), 0o700)?;
+
+    let res = rounds(&mut target, &local, &remote);
+
+    info!("Cleaning up {} …", target.display(&remote));
+    target.remove_dir_recursive(&remote, &|_| true)?;
+    let _ = fs::remove_dir_all(&local);
+
+    let results = res?;
+    println!("{:>10}  {:>6}  {:>10}  {:>12}  {:>10}", "size", "files", "time", "throughput", "files/s");
+    for r in results {
+        let secs = r.secs.max(f64::EPSILON);
+        println!(
+            "{:>10}  {:>6}  {:>9.2}s  {:>8.1} MiB/s  {:>10.1}",
+            human_bytes(r.size),
+            r.files,
+            r.secs,
+            (r.size * r.files as u64) as f64 / secs / (1 << 20) as f64,
+            r.files as f64 / secs,
+        );
+    }
+    Ok(())
+}
+
+struct RoundResult {
+    size: u64,
+    files: usize,
+    secs: f64,
+}
+
+fn rounds(target: &mut Target, local: &Path, remote: &Path) -> Result<Vec<RoundResult>> {
+    let mut results = Vec::new();
+    for (size, files) in ROUNDS {
+        let name = format!("{size}.bin");
+        let src = local.join(&name);
+        write_random(&src, size)?;
+
+        info!("Uploading {files} × {} …", human_bytes(size));
+        let start = Instant::now();
+        for i in 0..files {
+            // Same path as a real sync, including the mode/mtime setstat
+            target.copy_file(&src, &remote.join(format!("{i}-{name}")), None, 0o600, &mut |_| {})?;
+        }
+        results.push(RoundResult {
+            size,
+            files,
+            secs: start.elapsed().as_secs_f64(),
+        });
+    }
+    Ok(results)
+}
+
+/// Incompressible filler, so SSH compression can't flatter the numbers.
+fn write_random(path: &Path, size: u64) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path).with_context(|| format!("Creating {}", path.display()))?);
+    let mut x = RandomState::new().build_hasher().finish() | 1;
+    let mut written = 0;
+    while written < size {
+        // xorshift64
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        let n = (size - written).min(8);
+        out.write_all(&x.to_le_bytes()[..n as usize])?;
+        written += n;
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -1,12 +1,71 @@
-use clap::{ArgAction, Parser};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use encoding_rs::Encoding;
+use serde::Deserialize;
+
+use std::fs;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backend::Backend;
+use crate::hostkey::Fingerprint;
+use crate::logging::LogFormat;
+use crate::rename::RenameRule;
+use crate::{Conflict, WatchKind};
+use crate::target::{CompareMode, RemoteCase};
+use crate::transform::Transform;
 
 /// Simple, fast SFTP directory mirror: local -> remote
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(name = "rmote", author, version, about)]
 pub struct Cli {
-    /// Remote host (IP or DNS)
-    #[arg(long, env = "RMOTE_HOST")]
-    pub host: String,
+    /// Target as a URL, e.g. sftp://user@host:2222/srv/app (scp:// also accepted).
+    /// Explicit --host/--port/--user/--remote-dir flags take precedence.
+    #[arg(value_name = "TARGET")]
+    pub target: Option<String>,
+
+    /// Local directory to mirror and watch (defaults to the current directory).
+    /// May also be given alone when --host is used, e.g. `rmote --host h ./site`.
+    #[arg(value_name = "DIR")]
+    pub dir: Option<PathBuf>,
+
+    /// Remote host (IP or DNS). Repeat to mirror to several hosts at once.
+    #[arg(
+        long = "host",
+        env = "RMOTE_HOST",
+        value_delimiter = ',',
+        action = ArgAction::Append,
+        required_unless_present_any = ["target", "inventory", "s3_bucket"]
+    )]
+    pub hosts: Vec<String>,
+
+    /// What to mirror into: directories on SSH servers, or a bucket in an
+    /// S3-compatible object store through the `aws` CLI (see --s3-bucket)
+    #[arg(long, value_enum, default_value_t = Backend::Sftp)]
+    pub backend: Backend,
+
+    /// Bucket to mirror into with --backend s3. --remote-dir becomes the key
+    /// prefix; credentials and region come from the usual AWS CLI config
+    #[arg(long, value_name = "BUCKET", required_if_eq("backend", "s3"), conflicts_with_all = ["hosts", "inventory"])]
+    pub s3_bucket: Option<String>,
+
+    /// Endpoint of an S3-compatible store other than AWS (e.g. MinIO)
+    #[arg(long, value_name = "URL", requires = "s3_bucket")]
+    pub s3_endpoint_url: Option<String>,
+
+    /// Profile in the AWS CLI config to use with --backend s3
+    #[arg(long, value_name = "NAME", requires = "s3_bucket")]
+    pub s3_profile: Option<String>,
+
+    /// Read targets from this file instead, one `[user@]host[:port]:remote_dir`
+    /// per line (`#` comments allowed). Hosts that can't be reached are
+    /// reported and left out rather than stopping the run
+    #[arg(long, value_name = "FILE", conflicts_with = "hosts")]
+    pub inventory: Option<PathBuf>,
 
     /// Remote SSH port
     #[arg(long, env = "RMOTE_PORT", default_value = "22")]
@@ -28,9 +87,45 @@ pub struct Cli {
     #[arg(long, env = "RMOTE_PASSPHRASE")]
     pub passphrase: Option<String>,
 
-    /// Remote base directory to mirror into (created if needed)
-    #[arg(long, env = "RMOTE_REMOTE_DIR", default_value = ".")]
-    pub remote_dir: String,
+    /// Remote base directory to mirror into (created if needed).
+    /// With several --host entries, give one shared dir or one per host, in order.
+    /// With a single host, every dir gets the tree over the one connection.
+    #[arg(long = "remote-dir", env = "RMOTE_REMOTE_DIR", action = ArgAction::Append, default_value = ".")]
+    pub remote_dirs: Vec<String>,
+
+    /// Fail instead of creating the remote dir when it doesn't exist, so a
+    /// typo in it can't scatter files into a new directory
+    #[arg(long)]
+    pub no_create_remote_dir: bool,
+
+    /// Refuse to start unless the remote dir is empty (apart from excluded
+    /// paths), so a deploy can't mix with leftovers of a previous one
+    #[arg(long)]
+    pub require_empty_remote: bool,
+
+    /// Before syncing, keep probing (create and delete a file) until the
+    /// remote dir is writable, for at most this long, e.g. `2m` while an NFS
+    /// mount comes up
+    #[arg(long, value_name = "TIMEOUT", value_parser = parse_duration)]
+    pub wait_for_writable: Option<Duration>,
+
+    /// Do the initial sync with `rsync -az` over ssh where the
+    /// remote has rsync, then switch to SFTP for watching. Falls back to SFTP
+    /// if rsync is missing, fails, or can't honour the other options
+    #[arg(long)]
+    pub use_rsync: bool,
+
+    /// Do the initial sync as one tar stream into a remote `tar -x`, which
+    /// beats a round trip per file for trees of many small files. Falls back
+    /// to SFTP if tar is missing, fails, or can't honour the other options
+    #[arg(long, conflicts_with = "use_rsync")]
+    pub tar_bulk: bool,
+
+    /// Do the initial sync into `<remote_dir>.staging` and only then swap it
+    /// in for the remote dir, so the server never sees a half-updated tree.
+    /// The old tree is removed after the swap
+    #[arg(long, conflicts_with = "concurrent_initial")]
+    pub staging: bool,
 
     /// Perform a full sync at startup
     #[arg(long, action = ArgAction::SetTrue, default_value_t = true)]
@@ -40,12 +135,982 @@ pub struct Cli {
     #[arg(long, action = ArgAction::SetTrue, overrides_with = "initial_sync")]
     pub no_initial_sync: bool,
 
+    /// Run the initial sync on its own connection while already watching;
+    /// changes made meanwhile are sent once it finishes
+    #[arg(long, conflicts_with = "mirror_permissions_only")]
+    pub concurrent_initial: bool,
+
+    /// Instead of the initial sync, walk the tree once fixing remote modes that
+    /// differ from local ones without re-uploading identical files, then exit. Files that differ in
+    /// size or mtime are uploaded as usual.
+    #[arg(long)]
+    pub mirror_permissions_only: bool,
+
+    /// Instead of the initial sync, apply what `git diff --name-status <REF> HEAD`
+    /// lists (uploads, deletes, renames) and exit
+    #[arg(long, value_name = "REF", conflicts_with_all = ["mirror_permissions_only", "concurrent_initial"])]
+    pub since: Option<String>,
+
+    /// With --since, keep watching afterwards instead of exiting
+    #[arg(long, requires = "since")]
+    pub then_watch: bool,
+
+    /// Upload each file under a temporary name next to it and rename it into
+    /// place once complete, so the remote never sees a half-written file.
+    /// Temp files orphaned by earlier runs are removed at startup
+    #[arg(long)]
+    pub atomic: bool,
+
+    /// Upload files of at least this size (e.g. `512M`) in --split-parts
+    /// ranges at once, each over its own SSH connection, and verify the
+    /// result by checksum. Helps with big files on high-latency links
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub split_large: Option<u64>,
+
+    /// How many ranges --split-large cuts a file into
+    #[arg(long, value_name = "N", default_value_t = 4, requires = "split_large")]
+    pub split_parts: usize,
+
+    /// After the initial sync, hash every synced file locally and read every
+    /// remote copy back, check that the combined digests agree and print the
+    /// result. Expensive; meant for comparing environments
+    #[arg(long, conflicts_with = "dry_run")]
+    pub final_digest: bool,
+
+    /// Run this shell command on every target, in the remote dir, once the
+    /// initial sync has finished without failures (e.g. `npm ci && systemctl
+    /// restart app`). Its output is logged line by line
+    #[arg(long, value_name = "CMD")]
+    pub after_initial_sync: Option<String>,
+
+    /// Run --after-initial-sync even if --max-errors let some uploads fail
+    #[arg(long)]
+    pub run_hook_on_partial: bool,
+
+    /// Stop rmote if --after-initial-sync exits with a nonzero status
+    /// instead of only logging it
+    #[arg(long)]
+    pub hook_fatal: bool,
+
+    /// Let a config file run CMD, as --after-initial-sync,
+    /// --conflict-command or the command of a --transform rule. Commands from
+    /// files must match one of these exactly or rmote refuses to start;
+    /// commands given on the command line always run. May be repeated
+    #[arg(long, value_name = "CMD", action = ArgAction::Append)]
+    pub allow_command: Vec<String>,
+
+    /// Instead of watching the tree, take changed paths from stdin, one per
+    /// line (`-path` to delete), e.g. from `fswatch`. Exits when stdin closes
+    #[arg(long)]
+    pub stream: bool,
+
+    /// Connect, then sync nothing until SIGUSR1 or a `deploy` call on
+    /// --control-socket asks for a full sync, and stand by again after it.
+    /// The connections stay open meanwhile, so a deploy starts right away
+    #[arg(long, conflicts_with_all = ["stream", "concurrent_initial", "since", "mirror_permissions_only"])]
+    pub standby: bool,
+
+    /// Log every raw watcher event as it arrives, before any filtering, and
+    /// what each batch then decided to do per path. Works with --dry-run
+    #[arg(long)]
+    pub dump_events: bool,
+
+    /// Report what would change on the remote without touching it.
+    /// Files whose remote size and mtime already match are not reported.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// With --dry-run, print stable `A path`, `M path`, `D path` lines to stdout
+    /// (paths relative to the local root) instead of log messages
+    #[arg(long, requires = "dry_run")]
+    pub porcelain: bool,
+
+    /// With --dry-run, estimate how long the uploads would take at this many
+    /// bytes per second (e.g. `12M`; `rmote bench` measures it)
+    #[arg(long, value_name = "RATE", value_parser = parse_size, requires = "dry_run")]
+    pub link_speed: Option<u64>,
+
+    /// What to do when a file changed on the remote since rmote uploaded it
+    /// and changed locally as well
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = Conflict::LocalWins)]
+    pub conflict: Conflict,
+
+    /// Decide each conflict with this shell command instead of --conflict.
+    /// It gets the remote path and the local and remote mtimes as arguments
+    /// (and the host in RMOTE_HOST); exit 0 uploads, 1 skips, anything else
+    /// stops rmote
+    #[arg(long, value_name = "CMD")]
+    pub conflict_command: Option<String>,
+
+    /// Let deletes remove any remote path. By default only what rmote
+    /// uploaded, created or found identical is deleted, across runs, so content
+    /// placed in the remote dir by other means survives
+    #[arg(long)]
+    pub prune_all: bool,
+
+    /// Glob (relative to the remote dir) of remote paths deletes never
+    /// remove, e.g. `uploads`, even with --prune-all. May be repeated
+    #[arg(long, value_name = "GLOB", action = ArgAction::Append)]
+    pub keep: Vec<String>,
+
+    /// A delete that would remove more than this many remote files, or a
+    /// batch of events deleting more paths, prints what it covers and asks
+    /// first (or, without a terminal, is skipped unless --yes)
+    #[arg(long, value_name = "N", default_value_t = 50)]
+    pub confirm_over: usize,
+
+    /// Go ahead with large deletes without asking
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+
     /// One or more blacklist entries. May be repeated.
     /// Matches if a path equals an entry or starts with it.
     #[arg(long = "blacklist", short = 'x', action = ArgAction::Append)]
     pub blacklist: Vec<String>,
 
+    /// Also skip whatever git ignores: .gitignore files, .git/info/exclude and
+    /// core.excludesFile
+    #[arg(long)]
+    pub gitignore: bool,
+
+    /// Also skip what the local root's .dockerignore excludes, with Docker's
+    /// matching rules
+    #[arg(long)]
+    pub use_dockerignore: bool,
+
+    /// Glob (relative to the local root) that is uploaded by the initial sync
+    /// but not watched for changes afterwards. May be repeated.
+    #[arg(long = "no-watch-path", value_name = "GLOB", action = ArgAction::Append)]
+    pub no_watch_paths: Vec<String>,
+
+    /// Glob (relative to the local root) limiting which changes are picked up
+    /// while watching; the initial sync still covers everything. May be repeated.
+    #[arg(long = "watch-only-match", value_name = "GLOB", action = ArgAction::Append)]
+    pub watch_only: Vec<String>,
+
+    /// Rename files on the way to the remote: a regex matched against the
+    /// whole path relative to the local root, `=`, and its replacement with
+    /// `$1`-style groups, e.g. `(.*)\.dev\.(json)=$1.$2`. May be repeated;
+    /// the first matching rule applies
+    #[arg(long, value_name = "FROM=TO", action = ArgAction::Append, value_parser = RenameRule::parse)]
+    pub rename: Vec<RenameRule>,
+
+    /// Upload files matching GLOB (relative to the local root) as what the
+    /// shell command CMD prints with the file on stdin, e.g.
+    /// `*.js=terser -c -m`. The local file is left alone; if CMD fails, the
+    /// file is skipped. May be repeated; the first matching rule applies
+    #[arg(long, value_name = "GLOB=CMD", action = ArgAction::Append, value_parser = Transform::parse)]
+    pub transform: Vec<Transform>,
+
+    /// Change the case of every name below the remote dir. Two local names
+    /// that would end up the same are refused
+    #[arg(long, value_enum, value_name = "CASE", default_value_t = RemoteCase::Preserve)]
+    pub remote_case: RemoteCase,
+
+    /// Only sync files with one of these extensions, e.g. `rs,toml,md`
+    /// (case-insensitive, leading dot optional). Directories are still walked
+    #[arg(long, value_name = "EXT", value_delimiter = ',', value_parser = parse_ext)]
+    pub ext: Vec<String>,
+
+    /// Create every local directory on the remote during a full sync, even
+    /// if nothing inside gets uploaded. By default a directory is only created
+    /// once a file below it is sent, saving round trips on big trees
+    #[arg(long)]
+    pub preserve_empty_dirs: bool,
+
+    /// Create the whole directory tree on the remote before the initial sync
+    /// uploads any file, so tools watching the remote never see a file whose
+    /// sibling directories are still missing. Empty directories are created too
+    #[arg(long)]
+    pub dirs_first: bool,
+
+    /// Glob (relative to the local root) of files that only ever grow, like
+    /// logs: only the bytes beyond the remote copy's size are sent. May be
+    /// repeated
+    #[arg(long, value_name = "GLOB", action = ArgAction::Append)]
+    pub append_mode: Vec<String>,
+
+    /// While watching, don't upload changed files smaller than this (e.g.
+    /// `64`, `1K`), such as lock files and counters. The initial sync,
+    /// directories and deletes are unaffected
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub watch_min_size: Option<u64>,
+
+    /// Only sync text files (no NUL byte in the first 8 KiB)
+    #[arg(long, conflicts_with = "binary_only")]
+    pub text_only: bool,
+
+    /// Only sync binary files (a NUL byte in the first 8 KiB)
+    #[arg(long)]
+    pub binary_only: bool,
+
+    /// How to tell that a remote file is unchanged, so the initial sync,
+    /// full walks and watch events skip it: size, mtime, size+mtime or
+    /// checksum
+    #[arg(long, value_enum, value_name = "MODE", default_value = "size+mtime")]
+    pub compare_mode: CompareMode,
+
+    /// Make the initial sync compare by content whatever --compare-mode says,
+    /// reading each same-size remote file back and comparing SHA-256. Slow,
+    /// but ignores timestamps, e.g. when seeding a restored server
+    #[arg(long)]
+    pub checksum: bool,
+
+    /// Before a walk that compares files (the initial sync, reconcile,
+    /// --mirror-permissions-only), list the remote tree with one `find` over
+    /// SSH instead of a stat per file. Falls back to stat'ing if the server
+    /// has no GNU find
+    #[arg(long)]
+    pub fast_scan: bool,
+
+    /// Also copy setuid, setgid and sticky bits (mode & 0o7777 instead of 0o777).
+    /// This can create setuid executables on the remote.
+    #[arg(long)]
+    pub preserve_special_bits: bool,
+
+    /// Ignore local modes except whether the owner may execute: files land
+    /// as 0755 or 0644 (directories 0755), then --remote-umask if given
+    #[arg(long, conflicts_with = "preserve_special_bits")]
+    pub exec_bit_only: bool,
+
+    /// During full walks, recreate local hard links on the remote (with `ln`
+    /// over SSH) instead of uploading the same content once per name
+    #[arg(long)]
+    pub preserve_hardlinks: bool,
+
+    /// Follow symlinks: upload what they point at and descend into linked
+    /// directories, instead of recreating directory links as links
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Upload the contents behind symlinks that resolve outside the local
+    /// directory instead of skipping them with a warning
+    #[arg(long)]
+    pub allow_external_symlinks: bool,
+
+    /// Ask each remote login shell for its umask at startup and apply it to
+    /// the modes of uploaded files and created directories
+    #[arg(long)]
+    pub remote_umask: bool,
+
+    /// Mirror POSIX ACLs of uploaded files and created directories, read
+    /// with the local `getfacl` and applied with `setfacl` over SSH. Hosts
+    /// without `setfacl` are warned about and skipped
+    #[arg(long)]
+    pub preserve_acls: bool,
+
+    /// While watching, skip files whose mtime changed but whose content hash
+    /// matches what rmote last uploaded (e.g. build tools that `touch` files)
+    #[arg(long)]
+    pub skip_touch_only: bool,
+
+    /// Don't upload zero-byte files, such as the empty state some editors
+    /// leave for a moment before writing the real content. The remote copy
+    /// is left as it is
+    #[arg(long)]
+    pub skip_empty: bool,
+
+    /// Glob (relative to the local root) of files uploaded even when empty
+    /// with --skip-empty, e.g. `**/.gitkeep`. May be repeated.
+    #[arg(long, value_name = "GLOB", action = ArgAction::Append, requires = "skip_empty")]
+    pub keep_empty: Vec<String>,
+
+    /// Log overall progress of full syncs with throughput and ETAs, once a second
+    #[arg(long)]
+    pub progress: bool,
+
+    /// Keep a JSON snapshot of the progress of full syncs in this file
+    /// (percent, bytes, current file, ETAs), replaced at most once a second,
+    /// for a dashboard to poll when there's no terminal to log to
+    #[arg(long, value_name = "PATH")]
+    pub progress_file: Option<PathBuf>,
+
+    /// Which watch events trigger a sync, comma-separated
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "create,modify-data,modify-metadata,rename,remove"
+    )]
+    pub on_kinds: Vec<WatchKind>,
+
     /// Debounce window (seconds) to coalesce events
     #[arg(long, default_value_t = 1)]
     pub debounce_s: u64,
+
+    /// Exit cleanly once no file changes arrived for this long, e.g. to catch
+    /// late writes on a CI agent and then quit (e.g. 30s, 5m)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub idle_exit: Option<Duration>,
+
+    /// Cap on queued watch events. Beyond it the queue is dropped and replaced
+    /// by one size/mtime rescan of the tree, which bounds memory during bursts
+    #[arg(long, value_name = "N")]
+    pub max_queue: Option<usize>,
+
+    /// Upload at most this many files per flush of the event queue; the rest
+    /// stay queued, in path order, for the next debounce tick
+    #[arg(long, value_name = "N")]
+    pub max_batch_files: Option<NonZeroUsize>,
+
+    /// Log a failed upload or delete and carry on, until N of them failed
+    /// over the whole run; then stop. Without it, the first failure stops rmote
+    #[arg(long, value_name = "N")]
+    pub max_errors: Option<NonZeroUsize>,
+
+    /// Normalize event paths lexically instead of canonicalizing them, which
+    /// saves filesystem calls during bursts. Only safe if the local tree
+    /// holds no symlinked directories
+    #[arg(long)]
+    pub fast_paths: bool,
+
+    /// Accept JSON-RPC 2.0 calls on this unix socket while watching, one
+    /// request per line: status, rescan, pause, resume, blacklist.add,
+    /// blacklist.remove, sync and last_synced (and deploy with --standby)
+    #[arg(long, value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+
+    /// How many paths to remember the last upload time of, for the
+    /// `last_synced` control call; the least recently pushed are forgotten
+    #[arg(long, value_name = "N", default_value_t = 10_000)]
+    pub sync_history: usize,
+
+    /// Notice when the local directory path comes to name another directory,
+    /// e.g. after deploy tooling swapped it, then watch that one and reconcile
+    #[arg(long, conflicts_with = "stream")]
+    pub follow_root_swap: bool,
+
+    /// Write the PID to this file and hold a lock on it while running; refuse
+    /// to start if another rmote holds it. Removed again on exit
+    #[arg(long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Periodically re-walk the tree and re-send files whose remote size/mtime
+    /// differ, to heal missed watch events (e.g. 10m, 1h, or plain seconds)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub reconcile_interval: Option<Duration>,
+
+    /// Local IP address to connect from, for multi-homed machines, firewall
+    /// rules or VPN routing
+    #[arg(long, value_name = "IP")]
+    pub bind_address: Option<IpAddr>,
+
+    /// Only talk to servers presenting this host key, as printed by
+    /// `ssh-keygen -l`: `SHA256:<base64>` or `MD5:<hex>`. Checked right after
+    /// the handshake, before authenticating; applies to every host
+    #[arg(long, value_name = "FINGERPRINT", value_parser = Fingerprint::parse)]
+    pub host_key_fingerprint: Option<Fingerprint>,
+
+    /// Deliberately connect without verifying the server's host key. There
+    /// is no known_hosts check yet, so this is what happens without
+    /// --host-key-fingerprint anyway, but with the flag every connection
+    /// logs a warning naming the key it trusted. Once host keys are verified
+    /// by default, this flag will be what turns that off
+    #[arg(long, conflicts_with = "host_key_fingerprint")]
+    pub insecure_no_host_check: bool,
+
+    /// Reach the SSH server through this SOCKS5 proxy (no authentication).
+    /// The target host name is resolved by the proxy
+    #[arg(long, value_name = "HOST:PORT", value_parser = parse_host_port)]
+    pub socks5: Option<(String, u16)>,
+
+    /// Filename charset the server expects, e.g. latin1 or shift_jis (WHATWG
+    /// labels). Default: names are sent as the raw local bytes.
+    #[arg(long, value_name = "CHARSET", value_parser = parse_encoding)]
+    pub remote_encoding: Option<&'static Encoding>,
+
+    /// Retry the initial TCP connect and SSH handshake this many times, e.g.
+    /// while the server is still booting. Authentication failures are not retried.
+    #[arg(long, default_value_t = 0)]
+    pub connect_retries: u32,
+
+    /// Delay before the first connect retry; doubles each time (max 60s), with jitter
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1s")]
+    pub connect_retry_delay: Duration,
+
+    /// How many times to try reconnecting when the connection drops (0 disables)
+    #[arg(long, default_value_t = 3)]
+    pub reconnect_attempts: u32,
+
+    /// Seconds to wait before each reconnect attempt
+    #[arg(long, default_value_t = 5)]
+    pub reconnect_delay_s: u64,
+
+    /// After this many reconnects within --reconnect-window, wait
+    /// --reconnect-backoff between attempts instead (0 disables)
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    pub reconnect_storm: u32,
+
+    /// Time span in which --reconnect-storm counts reconnects
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "5m")]
+    pub reconnect_window: Duration,
+
+    /// Delay before each reconnect attempt once --reconnect-storm tripped
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "5m")]
+    pub reconnect_backoff: Duration,
+
+    /// Log output format; RUST_LOG adjusts the level filter
+    #[arg(long, value_enum, default_value_t = LogFormat::Plain)]
+    pub log_format: LogFormat,
+
+    /// Write the activity log to stdout; only warnings and errors stay on
+    /// stderr, so the two can be piped apart
+    #[arg(long)]
+    pub log_stdout: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Write a listing of the remote tree (path, size, mtime, mode) and exit,
+    /// e.g. to snapshot remote state and diff it later
+    ExportManifest {
+        /// Write the manifest here instead of stdout
+        #[arg(long, short = 'o', value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Also read every file back and add its SHA-256 (slow on large trees)
+        #[arg(long)]
+        hash: bool,
+    },
+
+    /// Upload synthetic files of several sizes to a scratch dir below the
+    /// remote dir, print the throughput per size, and clean up. Only the file
+    /// size varies; other settings are the ones given, so compare runs to
+    /// compare them
+    Bench,
+
+    /// Compare the local tree with the remote without changing anything:
+    /// print `M path` (differs), `A path` (missing remotely) and `D path`
+    /// (only on the remote) lines and exit non-zero if there are any.
+    /// Files are compared per --compare-mode
+    Verify,
+
+    /// Remove the temp files (`<name>.rmote-tmp.<token>.<n>`) that --atomic
+    /// uploads interrupted by a crash left on the remote, and exit. Nothing
+    /// else is touched. With --dry-run, only list them
+    CleanTemp {
+        /// Only remove temp files not written to for this long, e.g. `1h`
+        /// while another rmote may still be uploading
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "0s")]
+        min_age: Duration,
+    },
+}
+
+impl Cli {
+    /// Parse the command line, then fill in anything the URL-style target
+    /// provides that wasn't given explicitly.
+    pub fn load() -> Result<Self> {
+        let matches = Cli::command().get_matches();
+        let mut cli = Cli::from_arg_matches(&matches)?;
+
+        // A lone positional that isn't a URL is the local directory
+        if cli.dir.is_none()
+            && let Some(t) = cli.target.take_if(|t| !t.starts_with("sftp://") && !t.starts_with("scp://"))
+        {
+            cli.dir = Some(PathBuf::from(t));
+        }
+        if cli.inventory.is_some() && cli.target.is_some() {
+            bail!("--inventory can't be combined with a target URL");
+        }
+        if cli.s3_bucket.is_some() && cli.target.is_some() {
+            bail!("--s3-bucket can't be combined with a target URL");
+        }
+
+        // Config files rank below flags and env vars, and the URL target is a flag
+        if let Some(path) = user_config_path() {
+            cli.apply_config(&path, &matches)?;
+        }
+        let project = cli.dir.as_deref().unwrap_or(Path::new(".")).join(PROJECT_CONFIG);
+        cli.apply_config(&project, &matches)?;
+
+        if let Some(target) = cli.target.clone() {
+            cli.apply_target(&target, &matches)?;
+        }
+        // The hook may come from a config file, so clap can't check this
+        if cli.after_initial_sync.is_none() && (cli.run_hook_on_partial || cli.hook_fatal) {
+            bail!("--run-hook-on-partial and --hook-fatal need --after-initial-sync");
+        }
+        if cli.hosts.is_empty() && cli.inventory.is_none() && cli.backend != Backend::S3 {
+            bail!("No host given (use --host or an sftp:// target)");
+        }
+        Ok(cli)
+    }
+
+    /// The directory to mirror, resolved to an absolute path.
+    pub fn local_root(&self) -> Result<PathBuf> {
+        let Some(dir) = &self.dir else {
+            return std::env::current_dir().context("Getting current directory");
+        };
+        let root = std::fs::canonicalize(dir).with_context(|| format!("Local directory {}", dir.display()))?;
+        if !root.is_dir() {
+            bail!("{} is not a directory", dir.display());
+        }
+        std::fs::read_dir(&root).with_context(|| format!("Local directory {} is not readable", dir.display()))?;
+        Ok(root)
+    }
+
+    /// Pair every host with the remote directory it mirrors into, or read
+    /// them all from --inventory.
+    pub fn targets(&self) -> Result<Vec<Endpoint>> {
+        if let Some(path) = &self.inventory {
+            return read_inventory(path, self);
+        }
+        // One bucket, with the first --remote-dir as the key prefix
+        if self.backend == Backend::S3 {
+            let bucket = self.s3_bucket.clone().context("--backend s3 needs --s3-bucket")?;
+            return Ok(vec![Endpoint {
+                user: String::new(),
+                host: bucket,
+                port: 0,
+                dir: self.remote_dirs[0].clone(),
+            }]);
+        }
+        let endpoint = |host: &String, dir: &String| Endpoint {
+            user: self.user.clone(),
+            host: host.clone(),
+            port: self.port,
+            dir: dir.clone(),
+        };
+        let dirs = &self.remote_dirs;
+        if dirs.len() == 1 {
+            return Ok(self.hosts.iter().map(|h| endpoint(h, &dirs[0])).collect());
+        }
+        if let [host] = self.hosts.as_slice() {
+            return Ok(dirs.iter().map(|d| endpoint(host, d)).collect());
+        }
+        if dirs.len() != self.hosts.len() {
+            bail!(
+                "Got {} --remote-dir values for {} hosts; give one shared dir or exactly one per host",
+                dirs.len(),
+                self.hosts.len()
+            );
+        }
+        Ok(self.hosts.iter().zip(dirs).map(|(h, d)| endpoint(h, d)).collect())
+    }
+
+    /// Take defaults from a config file, if it exists, for whatever wasn't
+    /// given on the command line or in the environment. Blacklists add up.
+    /// Commands from the file have to be on the --allow-command list.
+    fn apply_config(&mut self, path: &Path, matches: &ArgMatches) -> Result<()> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        let config: FileConfig = toml::from_str(&text).with_context(|| format!("Parsing {}", path.display()))?;
+
+        if let Some(user) = config.user
+            && !explicit(matches, "user")
+        {
+            self.user = user;
+        }
+        if let Some(port) = config.port
+            && !explicit(matches, "port")
+        {
+            self.port = port;
+        }
+        if let Some(identity) = config.identity
+            && !explicit(matches, "identity")
+        {
+            self.identity = identity;
+        }
+        if let Some(identity_pub) = config.identity_pub
+            && !explicit(matches, "identity_pub")
+        {
+            self.identity_pub = identity_pub;
+        }
+        self.blacklist.extend(config.blacklist);
+
+        let allowed = |key: &str, cmd: &str| allow_command(&self.allow_command, path, key, cmd);
+        if let Some(cmd) = config.after_initial_sync
+            && !explicit(matches, "after_initial_sync")
+        {
+            allowed("after-initial-sync", &cmd)?;
+            self.after_initial_sync = Some(cmd);
+        }
+        if let Some(cmd) = config.conflict_command
+            && !explicit(matches, "conflict_command")
+        {
+            allowed("conflict-command", &cmd)?;
+            self.conflict_command = Some(cmd);
+        }
+        if !config.transform.is_empty() && !explicit(matches, "transform") {
+            let mut rules = Vec::with_capacity(config.transform.len());
+            for rule in &config.transform {
+                let rule = Transform::parse(rule).map_err(|e| anyhow!("{}: transform: {e}", path.display()))?;
+                allowed("transform", rule.cmd())?;
+                rules.push(rule);
+            }
+            self.transform = rules;
+        }
+        Ok(())
+    }
+
+    fn apply_target(&mut self, target: &str, matches: &ArgMatches) -> Result<()> {
+        let t = TargetUrl::parse(target)?;
+        let explicit = |id: &str| explicit(matches, id);
+
+        if !explicit("hosts") {
+            self.hosts = vec![t.host];
+        }
+        if let Some(port) = t.port
+            && !explicit("port")
+        {
+            self.port = port;
+        }
+        if let Some(user) = t.user
+            && !explicit("user")
+        {
+            self.user = user;
+        }
+        if let Some(dir) = t.path
+            && !explicit("remote_dirs")
+        {
+            self.remote_dirs = vec![dir];
+        }
+        Ok(())
+    }
+}
+
+/// Whether `id` came from the command line or the environment rather than a default.
+fn explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Per-project config file, looked up in the local directory.
+const PROJECT_CONFIG: &str = ".rmote.toml";
+
+/// Defaults from `.rmote.toml` or the user config; every key is optional.
+/// A project file comes with the checkout, so the commands here only run
+/// when --allow-command lists them, see `allow_command`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct FileConfig {
+    user: Option<String>,
+    port: Option<u16>,
+    identity: Option<String>,
+    identity_pub: Option<String>,
+    #[serde(default)]
+    blacklist: Vec<String>,
+    after_initial_sync: Option<String>,
+    conflict_command: Option<String>,
+    /// `GLOB=CMD` rules, as for --transform.
+    #[serde(default)]
+    transform: Vec<String>,
+}
+
+/// Refuse `cmd`, set as `key` in the config file `path`, unless the
+/// command line allowed exactly that command.
+fn allow_command(allowed: &[String], path: &Path, key: &str, cmd: &str) -> Result<()> {
+    if allowed.iter().any(|a| a == cmd) {
+        return Ok(());
+    }
+    bail!(
+        "{} sets {key} to {cmd:?}, which isn't allowed: commands from config files only run when given to --allow-command",
+        path.display()
+    )
+}
+
+/// `$XDG_CONFIG_HOME/rmote/config.toml`, falling back to `~/.config` when the
+/// variable is unset or not absolute, as the XDG spec asks.
+fn user_config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::home_dir().map(|h| h.join(".config")))?;
+    Some(base.join("rmote/config.toml"))
+}
+
+/// Pieces of an `sftp://[user@]host[:port][/path]` target.
+#[derive(Debug, PartialEq)]
+struct TargetUrl {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+    path: Option<String>,
+}
+
+impl TargetUrl {
+    fn parse(s: &str) -> Result<Self> {
+        let rest = s
+            .strip_prefix("sftp://")
+            .or_else(|| s.strip_prefix("scp://"))
+            .with_context(|| format!("Target {s:?} must start with sftp:// or scp://"))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], Some(&rest[i..])),
+            None => (rest, None),
+        };
+
+        let (user, hostport) = match authority.rsplit_once('@') {
+            Some((userinfo, hp)) => {
+                // The sftp URI draft allows ";fingerprint=..." parameters; ignore them.
+                let userinfo = userinfo.split(';').next().unwrap_or_default();
+                if userinfo.contains(':') {
+                    bail!("Passwords in target URLs are not supported; use key authentication");
+                }
+                (Some(percent_decode(userinfo)?), hp)
+            }
+            None => (None, authority),
+        };
+
+        let (host, port) = if let Some(v6) = hostport.strip_prefix('[') {
+            let (host, after) = v6
+                .split_once(']')
+                .with_context(|| format!("Unterminated IPv6 address in {s:?}"))?;
+            match after.strip_prefix(':') {
+                Some(p) => (host, Some(p)),
+                None if after.is_empty() => (host, None),
+                None => bail!("Unexpected {after:?} after IPv6 address in {s:?}"),
+            }
+        } else {
+            match hostport.rsplit_once(':') {
+                Some((h, p)) => (h, Some(p)),
+                None => (hostport, None),
+            }
+        };
+        if host.is_empty() {
+            bail!("Target {s:?} has no host");
+        }
+
+        let port = match port {
+            Some(p) if !p.is_empty() => {
+                Some(p.parse().with_context(|| format!("Invalid port {p:?} in {s:?}"))?)
+            }
+            _ => None,
+        };
+
+        // "/~/dir" means relative to the login directory, "/dir" is absolute.
+        let path = match path {
+            None | Some("/") => None,
+            Some(p) => {
+                let p = percent_decode(p)?;
+                match p.strip_prefix("/~") {
+                    Some("") => None,
+                    Some(rel) => Some(rel.trim_start_matches('/').to_string()),
+                    None => Some(p),
+                }
+            }
+        };
+
+        Ok(Self {
+            user: user.filter(|u| !u.is_empty()),
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+/// Where one target lives: who to log in as, on which server, and the
+/// directory mirrored into.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub dir: String,
+}
+
+impl Endpoint {
+    /// Whether `other` logs into the same account, so a session can be shared.
+    pub fn same_login(&self, other: &Endpoint) -> bool {
+        self.user == other.user && self.host == other.host && self.port == other.port
+    }
+}
+
+/// One `[user@]host[:port]:remote_dir` per line; blank lines and `#`
+/// comments are skipped. User and port default to --user and --port.
+fn read_inventory(path: &Path, cli: &Cli) -> Result<Vec<Endpoint>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Reading inventory {}", path.display()))?;
+    let mut endpoints = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let endpoint = parse_inventory_line(line, cli)
+            .with_context(|| format!("{}:{}: expected [user@]host[:port]:remote_dir, got {line:?}", path.display(), n + 1))?;
+        endpoints.push(endpoint);
+    }
+    if endpoints.is_empty() {
+        bail!("Inventory {} lists no hosts", path.display());
+    }
+    Ok(endpoints)
+}
+
+fn parse_inventory_line(line: &str, cli: &Cli) -> Result<Endpoint> {
+    let (user, rest) = match line.split_once('@') {
+        Some((user, rest)) => (user.to_string(), rest),
+        None => (cli.user.clone(), line),
+    };
+    // [::1]:22:/srv
+    let (host, rest) = match rest.strip_prefix('[') {
+        Some(r) => r.split_once("]:").context("unclosed [")?,
+        None => rest.split_once(':').context("no remote dir")?,
+    };
+    let (port, dir) = match rest.split_once(':') {
+        Some((port, dir)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            (port.parse().context("invalid port")?, dir)
+        }
+        _ => (cli.port, rest),
+    };
+    if user.is_empty() || host.is_empty() || dir.is_empty() {
+        bail!("empty field");
+    }
+    Ok(Endpoint {
+        user,
+        host: host.to_string(),
+        port,
+        dir: dir.to_string(),
+    })
+}
+
+/// Parse `90`, `90s`, `15m`, `2h` or `1d` into a duration.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("invalid duration {s:?}"))?;
+    let secs = match unit.trim() {
+        "" | "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        "d" => n * 86400,
+        "ms" => return Ok(Duration::from_millis(n)),
+        other => return Err(format!("unknown duration unit {other:?} (use ms, s, m, h or d)")),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parse `4096`, `64K`, `512M` or `2G` (powers of 1024, `iB`/`B` optional).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("invalid size {s:?}"))?;
+    let unit = unit.trim().trim_end_matches("iB").trim_end_matches('B');
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        other => return Err(format!("unknown size unit {other:?} (use K, M, G or T)")),
+    };
+    n.checked_mul(1 << shift).ok_or_else(|| format!("size {s:?} too large"))
+}
+
+fn parse_ext(s: &str) -> Result<String, String> {
+    match s.trim().trim_start_matches('.') {
+        "" => Err("empty extension".to_string()),
+        ext => Ok(ext.to_lowercase()),
+    }
+}
+
+fn parse_host_port(s: &str) -> Result<(String, u16), String> {
+    let (host, port) = s.rsplit_once(':').ok_or_else(|| format!("expected HOST:PORT, got {s:?}"))?;
+    let port = port.parse().map_err(|_| format!("invalid port {port:?}"))?;
+    // [::1]:1080
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return Err(format!("expected HOST:PORT, got {s:?}"));
+    }
+    Ok((host.to_string(), port))
+}
+
+fn parse_encoding(s: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(s.as_bytes()).ok_or_else(|| format!("unknown encoding {s:?}"))
+}
+
+fn percent_decode(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s
+                .get(i + 1..i + 3)
+                .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+                .with_context(|| format!("Invalid percent-encoding in {s:?}"))?;
+            out.push(hex);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).with_context(|| format!("{s:?} does not decode to UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_mode_defaults_to_size_mtime() {
+        let cli = Cli::parse_from(["rmote", "--host", "h"]);
+        assert_eq!(cli.compare_mode, CompareMode::SizeMtime);
+        let cli = Cli::parse_from(["rmote", "--host", "h", "--compare-mode", "size"]);
+        assert_eq!(cli.compare_mode, CompareMode::Size);
+    }
+
+    #[test]
+    fn config_file_keys() {
+        let config: FileConfig = toml::from_str("user = \"deploy\"\nblacklist = [\".git\"]").unwrap();
+        assert_eq!(config.user.as_deref(), Some("deploy"));
+        assert_eq!(config.blacklist, [".git"]);
+    }
+
+    /// `args` on the command line and `text` as a config file named `name`.
+    fn with_config(name: &str, args: &[&str], text: &str) -> Result<Cli> {
+        let path = std::env::temp_dir().join(format!("rmote-{name}-{}.toml", std::process::id()));
+        fs::write(&path, text).unwrap();
+        let matches = Cli::command().get_matches_from(["rmote", "--host", "h"].iter().chain(args));
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        let res = cli.apply_config(&path, &matches);
+        fs::remove_file(&path).unwrap();
+        res.map(|()| cli)
+    }
+
+    #[test]
+    fn config_file_commands_need_allowing() {
+        for (key, text) in [
+            ("after-initial-sync", "after-initial-sync = \"touch /tmp/owned\""),
+            ("conflict-command", "conflict-command = \"touch /tmp/owned\""),
+            ("transform", "transform = [\"*.js=touch /tmp/owned\"]"),
+        ] {
+            let err = with_config(key, &[], text).err().unwrap_or_else(|| panic!("{key} ran unasked"));
+            assert!(err.to_string().contains("--allow-command"), "{err}");
+            let cli = with_config(key, &["--allow-command", "touch /tmp/owned"], text).unwrap();
+            let cmd = match key {
+                "after-initial-sync" => cli.after_initial_sync.as_deref(),
+                "conflict-command" => cli.conflict_command.as_deref(),
+                _ => cli.transform.first().map(Transform::cmd),
+            };
+            assert_eq!(cmd, Some("touch /tmp/owned"));
+        }
+    }
+
+    #[test]
+    fn command_line_commands_need_no_allowing() {
+        let text = "after-initial-sync = \"make deploy\"";
+        let cli = with_config("cli-hook", &["--after-initial-sync", "make restart"], text).unwrap();
+        assert_eq!(cli.after_initial_sync.as_deref(), Some("make restart"));
+        let cli = with_config("cli-transform", &["--transform", "*.css=csso"], "").unwrap();
+        assert_eq!(cli.transform[0].cmd(), "csso");
+    }
+
+    #[test]
+    fn allowed_commands_match_exactly() {
+        let text = "conflict-command = \"./resolve.sh; rm -rf ~\"";
+        assert!(with_config("exact", &["--allow-command", "./resolve.sh"], text).is_err());
+    }
 }
@@ -0,0 +1,183 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use tracing::{debug, info, warn};
+
+/// What a client on the --control-socket asked for.
+pub enum Call {
+    /// Queue sizes, pause state, targets
+    Status,
+    /// One full rescan, like an overflowing --max-queue schedules
+    Rescan,
+    Pause,
+    Resume,
+    /// Add an entry as if given with --blacklist
+    BlacklistAdd(String),
+    /// Drop an entry added with --blacklist or `blacklist.add`
+    BlacklistRemove(String),
+    /// Queue a path (relative to the local root, or absolute) as changed
+    Sync(PathBuf),
+    /// When a path was last pushed, from the --sync-history
+    LastSynced(PathBuf),
+    /// Run a full sync now (--standby)
+    Deploy,
+}
+
+/// A call on its way to the dispatcher, which answers on `reply` with the
+/// JSON-RPC result or an error message.
+pub struct Request {
+    pub call: Call,
+    pub reply: Sender<Result<Value, String>>,
+}
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The dispatcher refused the call, e.g. an unknown blacklist entry
+const CALL_FAILED: i64 = -32000;
+
+/// Listen on `path` and pass every call on to `tx`. A stale socket left by
+/// an earlier run is replaced, but one that still accepts connections
+/// belongs to a running instance and is left alone. Any other file there
+/// is an error.
+pub fn serve(path: &Path, tx: Sender<Request>) -> Result<()> {
+    if let Ok(meta) = fs::symlink_metadata(path)
+        && meta.file_type().is_socket()
+    {
+        match UnixStream::connect(path) {
+            Ok(_) => bail!("Control socket {} is in use by another running rmote", path.display()),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                fs::remove_file(path).with_context(|| format!("Removing stale socket {}", path.display()))?;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Checking control socket {}", path.display())),
+        }
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("Binding control socket {}", path.display()))?;
+    info!("Control socket listening on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = client(stream, &tx) {
+                            debug!("[control] client gone: {e:#}");
+                        }
+                    });
+                }
+                Err(e) => warn!("[control] accept failed: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// One JSON-RPC request per line in, one response per line out.
+/// Notifications (no `id`) get no response.
+fn client(stream: UnixStream, tx: &Sender<Request>) -> Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, res) = match serde_json::from_str::<Value>(&line) {
+            Ok(req) => (req.get("id").cloned(), handle(&req, tx)),
+            Err(e) => (Some(Value::Null), Err((PARSE_ERROR, e.to_string()))),
+        };
+        let Some(id) = id else { continue };
+        let resp = match res {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+        };
+        writeln!(out, "{resp}")?;
+    }
+    Ok(())
+}
+
+fn handle(req: &Value, tx: &Sender<Request>) -> Result<Value, (i64, String)> {
+    if req.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err((INVALID_REQUEST, "expected a JSON-RPC 2.0 request object".into()));
+    }
+    let method = req
+        .get("method")
+        .and_then(Value::as_str)
+        .ok_or((INVALID_REQUEST, "missing method".into()))?;
+    let param = |name: &str| {
+        req.get("params")
+            .and_then(|p| p.get(name))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or((INVALID_PARAMS, format!("{method} needs a string param {name:?}")))
+    };
+    let call = match method {
+        "status" => Call::Status,
+        "rescan" => Call::Rescan,
+        "pause" => Call::Pause,
+        "resume" => Call::Resume,
+        "blacklist.add" => Call::BlacklistAdd(param("entry")?),
+        "blacklist.remove" => Call::BlacklistRemove(param("entry")?),
+        "sync" => Call::Sync(PathBuf::from(param("path")?)),
+        "last_synced" => Call::LastSynced(PathBuf::from(param("path")?)),
+        "deploy" => Call::Deploy,
+        _ => return Err((METHOD_NOT_FOUND, format!("unknown method {method:?}"))),
+    };
+
+    let (reply, rx) = mpsc::channel();
+    tx.send(Request { call, reply })
+        .map_err(|_| (CALL_FAILED, "rmote is shutting down".to_string()))?;
+    rx.recv()
+        .map_err(|_| (CALL_FAILED, "rmote is shutting down".to_string()))?
+        .map_err(|e| (CALL_FAILED, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rmote-control-{}-{name}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn stale_socket_is_replaced() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let (tx, _rx) = mpsc::channel();
+        serve(&path, tx).unwrap();
+        UnixStream::connect(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn live_socket_is_left_alone() {
+        let path = socket_path("live");
+        let _listener = UnixListener::bind(&path).unwrap();
+        let (tx, _rx) = mpsc::channel();
+        let err = serve(&path, tx).unwrap_err();
+        assert!(err.to_string().contains("in use"), "{err:#}");
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_an_error() {
+        let path = socket_path("file");
+        fs::write(&path, "").unwrap();
+        let (tx, _rx) = mpsc::channel();
+        assert!(serve(&path, tx).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{gitconfig_excludes_path, Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Globs matched against paths relative to the local root. A path matches if
+/// it or any of its parent directories does, so `vendor` covers `vendor/a/b`.
+pub struct PathGlobs {
+    set: GlobSet,
+}
+
+impl PathGlobs {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for p in patterns {
+            let glob = Glob::new(p.trim_end_matches('/')).with_context(|| format!("Invalid glob {p:?}"))?;
+            builder.add(glob);
+        }
+        Ok(Self { set: builder.build()? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    pub fn matches(&self, rel: &Path) -> bool {
+        !self.is_empty() && rel.ancestors().any(|p| !p.as_os_str().is_empty() && self.set.is_match(p))
+    }
+}
+
+/// Git's ignore rules for the local tree, resolved the way git does: the
+/// closest `.gitignore` wins over ones further up, then `.git/info/exclude`,
+/// then the user's `core.excludesFile`. Anything inside an ignored directory
+/// is ignored too.
+pub struct GitIgnore {
+    root: PathBuf,
+    /// `.gitignore` matchers keyed by the directory they live in.
+    dirs: HashMap<PathBuf, Gitignore>,
+    info_exclude: Gitignore,
+    global: Gitignore,
+}
+
+impl GitIgnore {
+    pub fn load(root: &Path) -> Result<Self> {
+        let repo = root.ancestors().find(|d| d.join(".git").exists());
+
+        let info_exclude = repo
+            .and_then(|r| git_dir(r).map(|git| load_ignore_file(&git.join("info/exclude"), r)))
+            .unwrap_or_else(Gitignore::empty);
+        let global = match gitconfig_excludes_path() {
+            Some(p) => load_ignore_file(&p, repo.unwrap_or(root)),
+            None => Gitignore::empty(),
+        };
+
+        let mut this = Self {
+            root: root.to_path_buf(),
+            dirs: HashMap::new(),
+            info_exclude,
+            global,
+        };
+
+        // .gitignore files in parent directories still inside the repo
+        if let Some(repo) = repo {
+            for dir in root.ancestors().skip(1).take_while(|d| d.starts_with(repo)) {
+                this.add_dir(dir);
+            }
+        }
+
+        // ...and everything below the root, skipping directories already ignored
+        let mut queue = vec![root.to_path_buf()];
+        while let Some(dir) = queue.pop() {
+            this.add_dir(&dir);
+            let Ok(entries) = fs::read_dir(&dir) else { continue };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.file_type().is_ok_and(|t| t.is_dir())
+                    && entry.file_name() != ".git"
+                    && !this.is_ignored(&path)
+                {
+                    queue.push(path);
+                }
+            }
+        }
+        Ok(this)
+    }
+
+    fn add_dir(&mut self, dir: &Path) {
+        let file = dir.join(".gitignore");
+        if file.is_file() {
+            self.dirs.insert(dir.to_path_buf(), load_ignore_file(&file, dir));
+        }
+    }
+
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        // Check each component from the top so an ignored parent hides its children
+        let mut current = self.root.clone();
+        let mut comps = rel.components().peekable();
+        while let Some(c) = comps.next() {
+            current.push(c);
+            let is_dir = comps.peek().is_some() || current.is_dir();
+            if self.matched(&current, is_dir) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn matched(&self, path: &Path, is_dir: bool) -> bool {
+        let closest_first = path
+            .ancestors()
+            .skip(1)
+            .filter_map(|d| self.dirs.get(d))
+            .chain([&self.info_exclude, &self.global]);
+        for gi in closest_first {
+            match gi.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+/// `.dockerignore` rules, which differ from git's: patterns are anchored at
+/// the root unless they start with `**`, `*` never crosses a `/`, the last
+/// matching line wins (`!` lines re-include), and a matched directory takes
+/// everything below it along.
+pub struct DockerIgnore {
+    root: PathBuf,
+    /// Matchers in file order, with whether a match excludes (false for `!`).
+    rules: Vec<(GlobMatcher, bool)>,
+}
+
+impl DockerIgnore {
+    pub fn load(root: &Path) -> Result<Self> {
+        let file = root.join(".dockerignore");
+        let text = match fs::read_to_string(&file) {
+            Ok(t) => t,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                warn!("No .dockerignore in {}", root.display());
+                String::new()
+            }
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", file.display())),
+        };
+
+        let mut rules = Vec::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (exclude, pattern) = match line.strip_prefix('!') {
+                Some(p) => (false, p.trim()),
+                None => (true, line),
+            };
+            // Docker cleans patterns like paths: "/a/./b/" is "a/b"
+            let pattern = pattern
+                .split('/')
+                .filter(|c| !c.is_empty() && *c != ".")
+                .collect::<Vec<_>>()
+                .join("/");
+            if pattern.is_empty() {
+                continue;
+            }
+            let glob = GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("Invalid .dockerignore pattern {line:?}"))?;
+            rules.push((glob.compile_matcher(), exclude));
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            rules,
+        })
+    }
+
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        let mut ignored = false;
+        for (glob, exclude) in &self.rules {
+            if rel.ancestors().any(|p| !p.as_os_str().is_empty() && glob.is_match(p)) {
+                ignored = *exclude;
+            }
+        }
+        ignored
+    }
+}
+
+/// The ignore files git reads besides the `.gitignore`s, for the tree at
+/// `root`: the repo's `.git/info/exclude` and the user's `core.excludesFile`,
+/// where they exist.
+pub fn git_exclude_files(root: &Path) -> Vec<PathBuf> {
+    let repo = root.ancestors().find(|d| d.join(".git").exists());
+    repo.and_then(git_dir)
+        .map(|git| git.join("info/exclude"))
+        .into_iter()
+        .chain(gitconfig_excludes_path())
+        .filter(|f| f.is_file())
+        .collect()
+}
+
+/// `.git` is usually a directory, but in worktrees and submodules it's a file
+/// pointing at the real one.
+fn git_dir(repo: &Path) -> Option<PathBuf> {
+    let dot_git = repo.join(".git");
+    if dot_git.is_dir() {
+        return Some(dot_git);
+    }
+    let content = fs::read_to_string(&dot_git).ok()?;
+    let target = content.trim().strip_prefix("gitdir:")?.trim();
+    Some(repo.join(target))
+}
+
+fn load_ignore_file(file: &Path, root: &Path) -> Gitignore {
+    if !file.is_file() {
+        return Gitignore::empty();
+    }
+    let mut builder = GitignoreBuilder::new(root);
+    if let Some(e) = builder.add(file) {
+        warn!("Problem reading {}: {e}", file.display());
+    }
+    builder.build().unwrap_or_else(|e| {
+        warn!("Ignoring {}: {e}", file.display());
+        Gitignore::empty()
+    })
+}
@@ -0,0 +1,55 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// One entry of `git diff --name-status`, paths relative to the local root.
+#[derive(Debug, PartialEq)]
+pub enum GitChange {
+    /// Added, modified, copied or changed type
+    Upload(PathBuf),
+    Delete(PathBuf),
+    Rename(PathBuf, PathBuf),
+}
+
+/// What changed between `rev` and `HEAD` below `root`.
+pub fn changes_since(root: &Path, rev: &str) -> Result<Vec<GitChange>> {
+    let out = Command::new("git")
+        .args(["diff", "--name-status", "-z", "-M", "--relative", rev, "HEAD", "--"])
+        .current_dir(root)
+        .output()
+        .context("Running git diff (is git installed?)")?;
+    if !out.status.success() {
+        bail!("git diff {rev} HEAD failed: {}", String::from_utf8_lossy(&out.stderr).trim());
+    }
+    parse_name_status(&out.stdout)
+}
+
+/// Parse `-z` output: `STATUS\0path\0`, or `STATUS\0old\0new\0` for renames and copies.
+fn parse_name_status(raw: &[u8]) -> Result<Vec<GitChange>> {
+    // Paths stay raw bytes; git doesn't promise they're UTF-8
+    let mut fields = raw.split(|&b| b == 0).filter(|f| !f.is_empty());
+
+    let mut changes = Vec::new();
+    while let Some(status) = fields.next() {
+        let mut path = || {
+            fields
+                .next()
+                .map(|f| PathBuf::from(OsStr::from_bytes(f)))
+                .with_context(|| format!("git diff: missing path after status {:?}", String::from_utf8_lossy(status)))
+        };
+        let change = match status.first() {
+            Some(b'A' | b'M' | b'T') => GitChange::Upload(path()?),
+            Some(b'D') => GitChange::Delete(path()?),
+            Some(b'R') => GitChange::Rename(path()?, path()?),
+            Some(b'C') => {
+                path()?;
+                GitChange::Upload(path()?)
+            }
+            _ => bail!("git diff: unexpected status {:?}", String::from_utf8_lossy(status)),
+        };
+        changes.push(change);
+    }
+    Ok(changes)
+}
@@ -0,0 +1,51 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// When each path was last pushed to the remote, for the `last_synced`
+/// control call. Holds at most `cap` paths; pushing one more forgets the
+/// path pushed longest ago, so huge trees don't grow it without bound.
+pub struct SyncHistory {
+    cap: usize,
+    /// Counts pushes, to order the paths by recency.
+    next: u64,
+    times: HashMap<PathBuf, (SystemTime, u64)>,
+    by_age: BTreeMap<u64, PathBuf>,
+}
+
+impl SyncHistory {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            cap,
+            next: 0,
+            times: HashMap::new(),
+            by_age: BTreeMap::new(),
+        }
+    }
+
+    /// Record `rel` as pushed at `at`.
+    pub fn record(&mut self, rel: &Path, at: SystemTime) {
+        if self.cap == 0 {
+            return;
+        }
+        self.next += 1;
+        if let Some((_, seq)) = self.times.insert(rel.to_path_buf(), (at, self.next)) {
+            self.by_age.remove(&seq);
+        }
+        self.by_age.insert(self.next, rel.to_path_buf());
+        if self.times.len() > self.cap
+            && let Some((_, oldest)) = self.by_age.pop_first()
+        {
+            self.times.remove(&oldest);
+        }
+    }
+
+    /// When `rel` was last pushed, unless that was never or too long ago.
+    pub fn get(&self, rel: &Path) -> Option<SystemTime> {
+        self.times.get(rel).map(|&(at, _)| at)
+    }
+
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{bail, Result};
+use ssh2::{HashType, Session};
+use std::fmt;
+
+/// A pinned host key (--host-key-fingerprint), in the formats `ssh-keygen -l`
+/// prints: `SHA256:<base64>` or `MD5:<hex pairs>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fingerprint {
+    /// Unpadded base64, compared as is
+    Sha256(String),
+    Md5([u8; 16]),
+}
+
+impl Fingerprint {
+    /// The prefix is case-insensitive, and a bare `aa:bb:…` is taken as MD5
+    /// like older OpenSSH printed it.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let bad = || format!("expected SHA256:<base64> or MD5:<hex>, got {s:?}");
+        let (kind, value) = s.split_once(':').ok_or_else(bad)?;
+        if kind.eq_ignore_ascii_case("sha256") {
+            let value = value.trim_end_matches('=');
+            if value.len() != 43 || !value.bytes().all(|b| BASE64.contains(&b)) {
+                return Err(bad());
+            }
+            return Ok(Self::Sha256(value.to_string()));
+        }
+        let hex = if kind.eq_ignore_ascii_case("md5") { value } else { s };
+        let bytes: Vec<u8> = hex
+            .split(':')
+            .map(|b| if b.len() == 2 { u8::from_str_radix(b, 16).ok() } else { None })
+            .collect::<Option<_>>()
+            .ok_or_else(bad)?;
+        bytes.try_into().map(Self::Md5).map_err(|_| bad())
+    }
+
+    /// SHA-256 fingerprint of the key the server behind `sess` presented.
+    pub fn of(sess: &Session) -> Option<Self> {
+        sess.host_key_hash(HashType::Sha256).map(|h| Self::Sha256(base64(h)))
+    }
+
+    /// Fail unless the server behind `sess` (after the handshake) presents this key.
+    pub fn check(&self, sess: &Session, host: &str) -> Result<()> {
+        let actual = match self {
+            Self::Sha256(_) => Self::of(sess),
+            Self::Md5(_) => sess
+                .host_key_hash(HashType::Md5)
+                .and_then(|h| h.try_into().ok())
+                .map(Self::Md5),
+        };
+        let Some(actual) = actual else {
+            bail!("{host} sent no host key to check against --host-key-fingerprint");
+        };
+        if actual != *self {
+            bail!("Host key of {host} is {actual}, expected {self} (--host-key-fingerprint)");
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sha256(b64) => write!(f, "SHA256:{b64}"),
+            Self::Md5(bytes) => {
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+                write!(f, "MD5:{}", hex.join(":"))
+            }
+        }
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 without padding, as OpenSSH prints fingerprints.
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    out
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::io;
+
+use clap::ValueEnum;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Bare messages, one per line
+    Plain,
+    /// One JSON object per event, including the enclosing spans
+    Json,
+}
+
+/// Install the default subscriber. `RUST_LOG` overrides the level filter.
+/// With `stdout`, only warnings and errors stay on stderr.
+///
+/// Embedders that want their own subscriber (OpenTelemetry etc.) can skip
+/// this and register one themselves; all activity goes through `tracing`.
+pub fn init(format: LogFormat, stdout: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("rmote=info,warn"));
+    let writer = if stdout {
+        BoxMakeWriter::new(io::stderr.with_max_level(Level::WARN).or_else(io::stdout))
+    } else {
+        BoxMakeWriter::new(io::stderr)
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+
+    match format {
+        LogFormat::Plain => builder.event_format(Plain).init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
+}
+
+/// Prints just the event's message and fields, like the old `eprintln!` output.
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
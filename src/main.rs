@@ -1,20 +1,61 @@
-use anyhow::{bail, Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use notify::event::{ModifyKind, RemoveKind};
 use notify::{RecommendedWatcher, RecursiveMode, Watcher, Event, EventKind};
-use ssh2::{Session, Sftp};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, VecDeque, HashSet};
 use std::fs::{self, File};
-use std::net::TcpStream;
+use std::io::{self, BufRead, IsTerminal, Read};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{MetadataExt};
-use std::path::{Path, PathBuf};
-use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command as Process;
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::Signals;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::env;
+use tracing::{debug, error, info, info_span, warn};
 
+mod acl;
+mod backend;
+mod bench;
 mod cli;
-
-use cli::Cli;
+mod control;
+mod filter;
+mod git;
+mod history;
+mod hostkey;
+mod logging;
+mod manifest;
+mod observer;
+mod owned;
+mod pidfile;
+mod progress;
+mod rename;
+mod retry;
+mod rsync;
+mod s3;
+mod socks;
+mod tar;
+mod target;
+mod transform;
+mod verify;
+
+use backend::Backend;
+use cli::{Cli, Command};
+use control::Call;
+use filter::{DockerIgnore, GitIgnore, PathGlobs};
+use history::SyncHistory;
+use git::GitChange;
+use observer::{LogObserver, Transfer, TransferObserver};
+use pidfile::PidFile;
+use progress::{human_bytes, human_duration, Progress};
+use target::{is_temp_name, CompareMode, RemoteCase, Target, TempNames, KEEPALIVE_SECS};
 
 #[derive(PartialEq, Debug, Copy, Clone)]
 enum Action {
@@ -23,411 +64,2441 @@ enum Action {
     None,
 }
 
+/// A remote change reported by `--dry-run`.
+#[derive(Debug, Copy, Clone)]
+enum Change {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl Change {
+    fn code(self) -> char {
+        match self {
+            Change::Added => 'A',
+            Change::Modified => 'M',
+            Change::Deleted => 'D',
+        }
+    }
+
+    fn verb(self) -> &'static str {
+        match self {
+            Change::Added => "add",
+            Change::Modified => "update",
+            Change::Deleted => "delete",
+        }
+    }
+}
+
 struct App {
-    // sess: Session,
-    sftp: Sftp,
+    cli: Cli,
+    targets: Vec<Target>,
     local_root: PathBuf,
-    remote_root: PathBuf,
+    /// Device and inode of the local root, for --follow-root-swap.
+    root_id: (u64, u64),
     blacklist: Vec<PathBuf>,
-    blacklist_names: HashSet<String>,
+    /// File names blacklisted anywhere, with the entry each came from.
+    blacklist_names: HashMap<String, PathBuf>,
+    gitignore: Option<GitIgnore>,
+    dockerignore: Option<DockerIgnore>,
+    /// Synced by `transfer_all` but ignored while watching.
+    no_watch: PathGlobs,
+    /// If set, the only paths watched (--watch-only-match).
+    watch_only: PathGlobs,
+    /// Uploaded by appending what's new (--append-mode).
+    append: PathGlobs,
+    /// Remote paths deletes never touch (--keep).
+    keep: PathGlobs,
+    /// Uploaded even when empty with --skip-empty (--keep-empty).
+    keep_empty: PathGlobs,
     debounce: Duration,
+    /// What a background initial sync already uploaded; queued events for
+    /// files that haven't changed since are dropped instead of re-sent.
+    initial_sent: Sent,
+    /// SHA-256 of each file as last uploaded, kept with --skip-touch-only.
+    hashes: HashMap<PathBuf, [u8; 32]>,
+    /// Everything this session uploaded or created, relative to the local
+    /// root. What deletes may touch is tracked per target, see `Target::own`.
+    synced: HashSet<PathBuf>,
+    /// Size and mtime of every file as this run last uploaded it, to tell
+    /// a remote edit apart from our own copy (--conflict).
+    last_synced: Sent,
+    /// When recently uploaded paths were pushed (--sync-history).
+    history: SyncHistory,
+    /// Remote names --rename handed out, to skip a second file mapped to one.
+    rename_claims: rename::Claims,
+    /// Told about every upload. The first one logs; --progress adds one for
+    /// the duration of a full sync.
+    observers: Vec<Box<dyn TransferObserver>>,
+    /// Where --atomic uploads are written before being renamed into place.
+    temp_names: TempNames,
+    /// --preserve-acls, unless the local getfacl is missing.
+    acls: bool,
+    /// Transfers that failed so far, counted against --max-errors.
+    errors: usize,
+    /// Files and bytes (summed over targets) a --dry-run pass would upload.
+    dry_run_files: usize,
+    dry_run_bytes: u64,
 }
 
-impl App {
-    fn connect(cli: &Cli) -> Result<Session> {
-        let tcp = TcpStream::connect((cli.host.as_str(), cli.port))
-            .with_context(|| format!("Connecting to {}:{}", cli.host, cli.port))?;
+/// How often --follow-root-swap checks the local root, and how long it
+/// waits for a missing one to come back.
+const ROOT_CHECK: Duration = Duration::from_secs(2);
+const ROOT_GONE: Duration = Duration::from_secs(30);
 
-        let mut sess = Session::new().expect("Failed to create SSH session");
-        sess.set_tcp_stream(tcp);
-        sess.handshake().context("SSH handshake failed")?;
+/// --atomic temp files untouched for this long are assumed orphaned and
+/// removed at startup. Active uploads keep theirs fresh with every write.
+const STALE_TEMP: Duration = Duration::from_secs(3600);
 
-        let privkey = expand_tilde(&cli.identity);
-        let pubkey = expand_tilde(&cli.identity_pub);
+/// Why `skip_file` leaves out a file whose extension isn't in --ext.
+const NOT_IN_EXT: &str = "extension not in --ext";
 
-        sess.userauth_pubkey_file(
-            &cli.user,
-            Some(Path::new(&pubkey)),
-            Path::new(&privkey),
-            cli.passphrase.as_deref(),
-        )
-        .with_context(|| "SSH public key authentication failed")?;
+/// Files uploaded by a walk, relative to the local root, with the size and
+/// mtime they had when sent.
+type Sent = HashMap<PathBuf, (u64, i64)>;
 
-        if !sess.authenticated() {
-            bail!("Authentication failed");
+impl App {
+    fn new(cli: &Cli) -> Result<Self> {
+        if cli.backend == Backend::S3 {
+            if let Some(opt) = s3::unsupported(cli) {
+                bail!("--backend s3 can't be combined with {opt}");
+            }
+            if !s3::available() {
+                bail!("--backend s3 needs the aws CLI, which wasn't found");
+            }
+        }
+        let local_root = cli.local_root()?;
+        let endpoints = cli.targets()?;
+        let multi = endpoints.len() > 1;
+        // Several roots on one host are told apart by their dir
+        let shared_host = endpoints.iter().enumerate().any(|(i, e)| endpoints[..i].iter().any(|o| o.host == e.host));
+        let mut targets: Vec<Target> = Vec::with_capacity(endpoints.len());
+        let mut failed = 0;
+        for endpoint in &endpoints {
+            let connected = match targets.iter().find(|t| t.endpoint.same_login(endpoint)) {
+                Some(other) => other.share(cli, endpoint),
+                None => Target::connect(cli, endpoint),
+            };
+            let mut t = match connected {
+                Ok(t) => t,
+                // A fleet deploy goes on without the hosts that are down
+                Err(e) if cli.inventory.is_some() => {
+                    error!("{}: {e:#}", endpoint.host);
+                    failed += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if shared_host {
+                t.label = Some(format!("{}:{}", endpoint.host, endpoint.dir));
+            } else if multi {
+                t.label = Some(endpoint.host.clone());
+            }
+            targets.push(t);
+        }
+        if cli.inventory.is_some() {
+            info!("Connected to {} of {} hosts ({failed} failed)", targets.len(), endpoints.len());
+            if targets.is_empty() {
+                bail!("None of the hosts in the inventory could be reached");
+            }
         }
-        Ok(sess)
+        Self::with_targets(cli, local_root, targets)
     }
 
-    fn new(cli: &Cli) -> Result<Self> {
-        let sess = Self::connect(cli)?;
-        let sftp = sess.sftp().context("Opening SFTP subsystem failed")?;
-
-        let local_root = std::env::current_dir().context("Getting current directory")?;
-        let remote_root = PathBuf::from(cli.remote_dir.clone());
+    /// The app mirroring `local_root` to `targets`, already connected, whose
+    /// remote roots get made ready.
+    fn with_targets(cli: &Cli, local_root: PathBuf, targets: Vec<Target>) -> Result<Self> {
+        let root_id = fs::metadata(&local_root).map(|m| (m.dev(), m.ino()))?;
 
         let blacklist_paths: Vec<PathBuf> = cli
             .blacklist
             .iter()
-            .map(|s| PathBuf::from(s))
+            .map(PathBuf::from)
             .collect();
 
-        let blacklist_names: HashSet<String> = cli
-            .blacklist
-            .iter()
-            .map(|s| Path::new(s).file_name().map(|n| n.to_string_lossy().to_string()))
-            .flatten()
-            .collect();
+        let blacklist_names = blacklist_names(&blacklist_paths);
+
+        let gitignore = if cli.gitignore { Some(GitIgnore::load(&local_root)?) } else { None };
+        let dockerignore = if cli.use_dockerignore { Some(DockerIgnore::load(&local_root)?) } else { None };
+
+        let acls = cli.preserve_acls && acl::available();
+        if cli.preserve_acls && !acls {
+            warn!("--preserve-acls: no getfacl found locally; ACLs are not mirrored");
+        }
 
         let app = Self {
-            // sess,
-            sftp,
+            cli: cli.clone(),
+            targets,
             local_root,
-            remote_root,
+            root_id,
             blacklist: blacklist_paths,
             blacklist_names,
+            gitignore,
+            dockerignore,
+            no_watch: PathGlobs::new(&cli.no_watch_paths)?,
+            watch_only: PathGlobs::new(&cli.watch_only)?,
+            append: PathGlobs::new(&cli.append_mode)?,
+            keep: PathGlobs::new(&cli.keep)?,
+            keep_empty: PathGlobs::new(&cli.keep_empty)?,
             debounce: Duration::from_secs(cli.debounce_s),
+            initial_sent: Sent::new(),
+            hashes: HashMap::new(),
+            observers: vec![Box::new(LogObserver)],
+            synced: HashSet::new(),
+            last_synced: Sent::new(),
+            history: SyncHistory::new(cli.sync_history),
+            rename_claims: rename::Claims::default(),
+            temp_names: TempNames::default(),
+            acls,
+            errors: 0,
+            dry_run_files: 0,
+            dry_run_bytes: 0,
         };
 
-        // Ensure remote root exists
-        app.ensure_remote_dir(None, 0o755)?;
+        if cli.no_create_remote_dir {
+            for t in &app.targets {
+                if !t.is_dir(&t.root)? {
+                    bail!("Remote directory {} does not exist (--no-create-remote-dir)", t.display(&t.root));
+                }
+            }
+        }
+
+        // Ensure remote roots exist
+        if !cli.dry_run && !matches!(cli.command, Some(Command::Verify)) {
+            for t in &app.targets {
+                t.ensure_dir(None, 0o755)?;
+                if let Some(timeout) = cli.wait_for_writable {
+                    t.wait_writable(timeout)?;
+                }
+                if cli.atomic {
+                    let n = t.clean_temp(STALE_TEMP, false)?;
+                    if n > 0 {
+                        info!("Removed {n} stale temp files from {}", t.display(&t.root));
+                    }
+                }
+                if cli.require_empty_remote {
+                    app.require_empty(t)?;
+                }
+            }
+        }
         Ok(app)
     }
 
+    /// --require-empty-remote: bail if the root of `t` holds anything but
+    /// excluded paths and --atomic temp files, naming the first few.
+    fn require_empty(&self, t: &Target) -> Result<()> {
+        let mut found = Vec::new();
+        for (remote, _) in t.sftp()?.readdir(&t.root).with_context(|| format!("readdir {}", t.display(&t.root)))? {
+            let rel = t.local_name(remote.strip_prefix(&t.root).unwrap_or(&remote));
+            if !is_temp_name(&remote) && !self.is_blacklisted(&self.local_root.join(&rel)) {
+                found.push(rel);
+            }
+        }
+        if found.is_empty() {
+            return Ok(());
+        }
+        found.sort();
+        let mut shown: Vec<String> = found.iter().take(5).map(|p| p.display().to_string()).collect();
+        if found.len() > shown.len() {
+            shown.push(format!("and {} more", found.len() - shown.len()));
+        }
+        bail!(
+            "{} is not empty (--require-empty-remote): {}. Clean it up or deploy into a fresh directory",
+            t.display(&t.root),
+            shown.join(", ")
+        );
+    }
+
     fn run(mut self, cli: &Cli) -> Result<()> {
-        let initial = cli.initial_sync && !cli.no_initial_sync;
-        if initial {
-            eprintln!("Starting initial sync …");
-            self.transfer_all()?;
-            eprintln!("Initial sync complete.");
+        if cli.standby {
+            return self.standby();
+        }
+        if let Some(rev) = &cli.since {
+            let _span = info_span!("since", rev = rev.as_str()).entered();
+            self.sync_since(rev)?;
+            if !cli.then_watch {
+                return Ok(());
+            }
         }
 
-        let (w_tx, w_rx) = mpsc::channel::<notify::Result<Event>>();
-        let (m_tx, m_rx) = mpsc::channel::<Event>();
+        let initial = cli.initial_sync
+            && !cli.no_initial_sync
+            && !cli.mirror_permissions_only
+            && cli.since.is_none();
+        let mut background = None;
+        if initial && cli.concurrent_initial {
+            // The sync gets its own connections so the dispatcher can keep queuing
+            let cli = cli.clone();
+            background = Some(thread::spawn(move || {
+                let _span = info_span!("initial_sync").entered();
+                info!("Starting initial sync in the background …");
+                let mut app = App::new(&cli)?;
+                let sent = app.initial_sync()?;
+                if cli.final_digest {
+                    app.final_digest()?;
+                }
+                app.after_initial_sync(0)?;
+                Ok(sent)
+            }));
+        } else if initial {
+            let _span = info_span!("initial_sync").entered();
+            info!("Starting initial sync …");
+            let errors = self.errors;
+            self.initial_sync()?;
+            info!("Initial sync complete.");
+            if cli.final_digest {
+                self.final_digest()?;
+            }
+            self.after_initial_sync(errors)?;
+        }
 
-        let mut watcher: RecommendedWatcher =
-            notify::recommended_watcher(move |res| {
-                let _ = w_tx.send(res);
-            }).context("Creating file watcher")?;
+        if cli.mirror_permissions_only {
+            let _span = info_span!("permissions").entered();
+            info!("Reconciling remote permissions …");
+            self.transfer_all(Pass::Permissions)?;
+            info!("Permissions pass complete.");
+            return Ok(());
+        }
 
-        watcher
-            .watch(Path::new("."), RecursiveMode::Recursive)
-            .context("Starting watch on current directory")?;
+        let (m_tx, m_rx) = mpsc::channel::<Event>();
+
+        let watcher = if cli.stream {
+            // Thread: turn lines on stdin into events; ends when stdin closes
+            let root = self.local_root.clone();
+            thread::spawn(move || {
+                if let Err(e) = stdin_event_receiver(&root, m_tx) {
+                    error!("[stdin] error: {e:#}");
+                }
+            });
+            None
+        } else {
+            let (w_tx, w_rx) = mpsc::channel::<notify::Result<Event>>();
+            let mut watcher: RecommendedWatcher =
+                notify::recommended_watcher(move |res| {
+                    let _ = w_tx.send(res);
+                }).context("Creating file watcher")?;
+
+            watcher
+                .watch(&self.local_root, RecursiveMode::Recursive)
+                .with_context(|| format!("Starting watch on {}", self.local_root.display()))?;
+
+            // Thread: turn notify results into raw events for our dispatcher
+            let kinds = cli.on_kinds.clone();
+            let dump = cli.dump_events;
+            thread::spawn(move || {
+                if let Err(e) = file_event_receiver(w_rx, m_tx, &kinds, dump) {
+                    error!("[watcher] error: {e:#}");
+                }
+            });
+            Some(watcher)
+        };
 
-        // Thread: turn notify results into raw events for our dispatcher
-        let tx = m_tx.clone();
-        let _h_watcher = thread::spawn(move || {
-            if let Err(e) = file_event_receiver(w_rx, tx) {
-                eprintln!("[watcher] error: {e:#}");
+        let control = match &cli.control_socket {
+            Some(path) => {
+                let (c_tx, c_rx) = mpsc::channel();
+                control::serve(path, c_tx)?;
+                Some(c_rx)
             }
-        });
+            None => None,
+        };
 
-        // Dispatcher loop in the main thread (has access to &mut self.sftp)
-        if let Err(e) = self.dispatcher(m_rx) {
-            eprintln!("[dispatcher] error: {e:#}");
+        // Dispatcher loop in the main thread (owns the SFTP connections)
+        if let Err(e) = self.dispatcher(m_rx, background, control, watcher) {
+            error!("[dispatcher] error: {e:#}");
         }
 
         Ok(())
     }
 
-    fn transfer_all(&mut self) -> Result<()> {
-        let mut queue: VecDeque<PathBuf> = VecDeque::new();
-        queue.push_back(self.local_root.clone());
-
-        while let Some(dir) = queue.pop_front() {
-            for entry in fs::read_dir(&dir).with_context(|| format!("Reading {:?}", dir))? {
-                let entry = entry?;
-                let path = entry.path();
-
-                if self.is_blacklisted(&path) {
-                    continue;
-                }
-
-                let rel = self.rel(&path)?;
-                let remote = self.remote_root.join(&rel);
-
-                let meta = entry.metadata()?;
-                let mode: i32 = (meta.mode() & 0o777) as i32;
+    /// The initial upload. With --use-rsync or --tar-bulk, rsync or a tar
+    /// stream sends the bulk where it can and a size/mtime pass picks up
+    /// whatever it left out; otherwise, or if that fails, a full walk over SFTP.
+    fn initial_sync(&mut self) -> Result<Sent> {
+        if self.cli.staging && !self.cli.dry_run {
+            return self.staged_sync();
+        }
+        let bulk = !self.cli.dry_run
+            && (self.cli.use_rsync && self.rsync_all() || self.cli.tar_bulk && self.tar_all());
+        if !bulk {
+            return self.transfer_all(Pass::Full);
+        }
+        self.transfer_all(Pass::Changed)?;
+        // Everything on the remote now counts as sent, as after a full walk
+        let sent: Sent = self
+            .synced
+            .iter()
+            .filter_map(|rel| {
+                let meta = fs::metadata(self.local_root.join(rel)).ok().filter(|m| m.is_file())?;
+                Some((rel.clone(), (meta.size(), meta.mtime())))
+            })
+            .collect();
+        let now = SystemTime::now();
+        sent.keys().for_each(|rel| self.history.record(rel, now));
+        Ok(sent)
+    }
 
-                if meta.is_dir() {
-                    self.ensure_remote_dir(Some(&remote), mode)?;
-                    queue.push_back(path);
-                } else if meta.is_file() {
-                    self.ensure_remote_dir(Some(remote.parent().unwrap()), 0o755)?;
-                    self.copy_file_to_remote(&path, &remote, mode)?;
-                }
+    /// Run --after-initial-sync on every target, unless uploads failed during
+    /// the sync (the error count went past `errors`) and --run-hook-on-partial
+    /// isn't set. A nonzero exit status only warns without --hook-fatal.
+    fn after_initial_sync(&self, errors: usize) -> Result<()> {
+        let Some(cmd) = &self.cli.after_initial_sync else { return Ok(()) };
+        if self.cli.dry_run {
+            info!("Would run after the initial sync: {cmd}");
+            return Ok(());
+        }
+        let failed = self.errors - errors;
+        if failed > 0 && !self.cli.run_hook_on_partial {
+            warn!("Not running --after-initial-sync: {failed} uploads failed (see --run-hook-on-partial)");
+            return Ok(());
+        }
+        for t in &self.targets {
+            let _span = info_span!("after_initial_sync", host = t.endpoint.host.as_str()).entered();
+            info!("Running on {}: {cmd}", t.display(&t.root));
+            let status = t
+                .run_in_root(cmd, &mut |line| info!("| {line}"))
+                .with_context(|| format!("Running --after-initial-sync on {}", t.endpoint.host))?;
+            match status {
+                0 => info!("--after-initial-sync finished on {}", t.endpoint.host),
+                _ if self.cli.hook_fatal => bail!("--after-initial-sync exited with {status} on {}", t.endpoint.host),
+                _ => warn!("--after-initial-sync exited with {status} on {}", t.endpoint.host),
             }
         }
         Ok(())
     }
 
-    fn dispatcher(&mut self, m_rx: Receiver<Event>) -> Result<()> {
-        let mut last_tick = Instant::now();
-        let mut events = VecDeque::new();
-
-        loop {
-            match m_rx.try_recv() {
-                Ok(ev) => events.push_back(ev),
-                Err(TryRecvError::Disconnected) => {
-                    eprintln!("Event channel disconnected; exiting.");
-                    break;
-                }
-                Err(TryRecvError::Empty) => (),
+    /// --staging: a full walk into `<root>.staging` on every target, which
+    /// only replaces the root once all of them got everything.
+    fn staged_sync(&mut self) -> Result<Sent> {
+        let mut roots = Vec::with_capacity(self.targets.len());
+        for t in &mut self.targets {
+            let staging = t.sibling("staging")?;
+            if t.exists(&staging)? {
+                info!("Removing leftover {}", t.display(&staging));
+                t.remove_dir_recursive(&staging, &|_| true)?;
             }
+            roots.push(std::mem::replace(&mut t.root, staging));
+            // The staged tree replaces the old one, and all of it is ours
+            t.disown_all();
+            t.ensure_dir(None, 0o755)?;
+        }
+        let sent = self.transfer_all(Pass::Full);
+        let mut staged = Vec::with_capacity(roots.len());
+        for (t, root) in self.targets.iter_mut().zip(roots) {
+            staged.push(std::mem::replace(&mut t.root, root));
+        }
+        let sent = sent?;
+        for (t, staging) in self.targets.iter_mut().zip(staged) {
+            t.swap_in(&staging)?;
+            info!("Swapped {} into place", t.display(&staging));
+        }
+        Ok(sent)
+    }
 
-            if last_tick.elapsed() >= self.debounce {
-                last_tick = Instant::now();
-                self.process_events(&mut events)?;
+    /// rsync the tree into every target. False, after a warning saying why,
+    /// if the initial sync has to go over SFTP instead. Remote files missing
+    /// locally are only deleted with --prune-all, and past --confirm-over
+    /// only once confirmed.
+    fn rsync_all(&self) -> bool {
+        if let Some(opt) = rsync::unsupported(&self.cli) {
+            warn!("--use-rsync can't be combined with {opt}; syncing over SFTP");
+            return false;
+        }
+        if !rsync::available() {
+            warn!("--use-rsync: no rsync found locally; syncing over SFTP");
+            return false;
+        }
+        if let Some(t) = self.targets.iter().find(|t| !t.has_command("rsync")) {
+            warn!("--use-rsync: no rsync on {}; syncing over SFTP", t.endpoint.host);
+            return false;
+        }
+        for t in &self.targets {
+            let delete = match self.rsync_deletes(t) {
+                Ok(delete) => delete,
+                Err(e) => {
+                    warn!("{e:#}; syncing over SFTP");
+                    return false;
+                }
+            };
+            info!("rsync: {} -> {}", self.local_root.display(), t.display(&t.root));
+            match rsync::command(&self.cli, &t.endpoint, &self.local_root, delete).status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    warn!("rsync to {} {status}; syncing over SFTP", t.endpoint.host);
+                    return false;
+                }
+                Err(e) => {
+                    warn!("Running rsync failed: {e}; syncing over SFTP");
+                    return false;
+                }
             }
+        }
+        true
+    }
 
-            // Keep CPU calm
-            thread::sleep(Duration::from_millis(10));
+    /// Whether rsync may pass `--delete` for `t`: only with --prune-all, and
+    /// when that removes more than --confirm-over paths, only if confirmed.
+    fn rsync_deletes(&self, t: &Target) -> Result<bool> {
+        if !self.cli.prune_all {
+            return Ok(false);
         }
-        Ok(())
+        if self.cli.yes {
+            return Ok(true);
+        }
+        let n = rsync::count_deletes(rsync::command(&self.cli, &t.endpoint, &self.local_root, true))?;
+        if n <= self.cli.confirm_over || confirm(&format!("rsync would remove {n} paths from {}", t.display(&t.root)))? {
+            return Ok(true);
+        }
+        warn!("Not deleting on {}; rsync only uploads", t.endpoint.host);
+        Ok(false)
     }
 
-    /// Coalesce many events per path into a minimal action list.
-    fn process_events(&mut self, events: &mut VecDeque<Event>) -> Result<()> {
-        let mut per_path: HashMap<PathBuf, Vec<EventKind>> = HashMap::new();
+    /// Stream the tree as tar into every target. False, after a warning
+    /// saying why, if the initial sync has to go over SFTP instead.
+    fn tar_all(&self) -> bool {
+        if let Some(opt) = tar::unsupported(&self.cli) {
+            warn!("--tar-bulk can't be combined with {opt}; syncing over SFTP");
+            return false;
+        }
+        if let Some(t) = self.targets.iter().find(|t| !t.has_command("tar")) {
+            warn!("--tar-bulk: no tar on {}; syncing over SFTP", t.endpoint.host);
+            return false;
+        }
+        let entries = match self.tar_files() {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("--tar-bulk: {e:#}; syncing over SFTP");
+                return false;
+            }
+        };
+        let files: Vec<_> = entries.iter().filter(|(_, meta)| meta.is_file()).collect();
+        let bytes: u64 = files.iter().map(|(_, meta)| meta.size()).sum();
+        for t in &self.targets {
+            info!("tar: {} files ({}) -> {}", files.len(), human_bytes(bytes), t.display(&t.root));
+            let res = t.ensure_dir(None, 0o755).and_then(|_| {
+                t.untar(|out| {
+                    for (rel, meta) in &entries {
+                        let remote = t.remote_path(rel);
+                        let name = remote.strip_prefix(&t.root).unwrap_or(&remote);
+                        let mode = t.masked(self.mode_for(meta)) as u32;
+                        if meta.is_dir() {
+                            tar::append_dir(out, name, mode, meta.mtime())?;
+                        } else {
+                            let local = self.local_root.join(rel);
+                            tar::append(out, name, &local, mode, meta.size(), meta.mtime())?;
+                        }
+                    }
+                    tar::finish(out)
+                })
+            });
+            if let Err(e) = res {
+                warn!("tar to {} failed: {e:#}; syncing over SFTP", t.endpoint.host);
+                return false;
+            }
+        }
+        true
+    }
 
-        while let Some(e) = events.pop_front() {
-            for p in e.paths {
-                // Absolutize to compare reliably; ignore errors quietly
-                let full = std::fs::canonicalize(&p).unwrap_or(p.clone());
-                per_path.entry(full).or_default().push(e.kind.clone());
+    /// The regular files the full walk would upload, relative to the local
+    /// root, preceded by the directories it would create for them, parents
+    /// first. Symlinks are left to the size/mtime pass after the tar stream.
+    fn tar_files(&self) -> Result<Vec<(PathBuf, fs::Metadata)>> {
+        let (mut dirs, mut files) = (Vec::new(), Vec::new());
+        let mut claims = rename::Claims::default();
+        let exists = |p: &Path| self.local_root.join(p).exists();
+        let mut queue = VecDeque::from([self.local_root.clone()]);
+        while let Some(dir) = queue.pop_front() {
+            for entry in fs::read_dir(&dir).with_context(|| format!("Reading {:?}", dir))? {
+                let path = entry?.path();
+                if self.is_blacklisted(&path) {
+                    continue;
+                }
+                let meta = fs::symlink_metadata(&path)?;
+                if meta.is_dir() {
+                    dirs.push((path.strip_prefix(&self.local_root)?.to_path_buf(), meta));
+                    queue.push_back(path);
+                } else if meta.is_file() && !self.skip_file(&path, &meta) {
+                    let rel = path.strip_prefix(&self.local_root)?.to_path_buf();
+                    // Left to the pass after the stream, which warns about it
+                    if claims.clash(&self.cli.rename, &rel, exists).is_none() {
+                        files.push((rel, meta));
+                    }
+                }
             }
         }
+        // Like the walk, only directories something goes into, unless asked for all
+        if !self.cli.preserve_empty_dirs && !self.cli.dirs_first {
+            let used: HashSet<&Path> = files.iter().flat_map(|(rel, _)| rel.ancestors().skip(1)).collect();
+            dirs.retain(|(dir, _)| used.contains(dir.as_path()));
+        }
+        dirs.append(&mut files);
+        Ok(dirs)
+    }
 
-        for (path, kinds) in per_path {
-            if self.is_blacklisted(&path) {
-                continue;
+    /// Full upload of the local tree. If the connection drops midway, reconnect
+    /// and carry on from where the walk stopped instead of starting over.
+    /// Returns the files that were uploaded.
+    fn transfer_all(&mut self, pass: Pass) -> Result<Sent> {
+        let mut walk = Walk::new(self.local_root.clone(), pass);
+        let observers = self.observers.len();
+        // Not what watching would have uploaded in between
+        (self.dry_run_files, self.dry_run_bytes) = (0, 0);
+        if pass == Pass::Full && (self.cli.progress || self.cli.progress_file.is_some()) && !self.cli.dry_run {
+            let total = self.local_bytes(&self.local_root) * self.targets.len() as u64;
+            let progress = Progress::new(total, self.cli.progress, self.cli.progress_file.clone());
+            self.observers.push(Box::new(progress));
+        }
+        if pass == Pass::Full && self.cli.dirs_first && !self.cli.dry_run {
+            info!("Creating directories …");
+            let made = self.make_skeleton(&self.local_root.clone())?;
+            info!("Created {made} directories; uploading files …");
+        }
+        let scan = self.cli.fast_scan;
+        if scan {
+            self.targets.iter_mut().for_each(Target::fast_scan);
+        }
+        let res = self.resume_walk(&mut walk);
+        if scan {
+            self.targets.iter_mut().for_each(Target::end_scan);
+        }
+        self.observers.truncate(observers);
+        self.save_owned();
+        if self.cli.dry_run && pass != Pass::Permissions && res.is_ok() {
+            self.estimate();
+        }
+        res.map(|()| walk.sent)
+    }
+
+    /// Sum up what a --dry-run walk would have uploaded, with a rough time
+    /// at --link-speed, and start counting afresh.
+    fn estimate(&mut self) {
+        let files = std::mem::take(&mut self.dry_run_files);
+        let bytes = std::mem::take(&mut self.dry_run_bytes);
+        let mut line = format!("dry-run: would upload {} in {files} files", human_bytes(bytes));
+        match self.cli.link_speed {
+            Some(speed) => {
+                line += &format!(
+                    "; estimated {} at an assumed {}/s (--link-speed)",
+                    human_duration(bytes as f64 / speed.max(1) as f64),
+                    human_bytes(speed)
+                );
             }
+            None => line += "; give --link-speed for a time estimate",
+        }
+        info!("{line}");
+    }
 
-            let mut actions: Vec<Action> = Vec::new();
-            let mut last = Action::None;
+    /// Drive `walk` to completion, reconnecting as needed.
+    fn resume_walk(&mut self, walk: &mut Walk) -> Result<()> {
+        let mut stalled = 0;
 
-            for k in kinds {
-                let action = match k {
-                    EventKind::Create(_) | EventKind::Modify(_) => Action::Transfer,
-                    EventKind::Remove(_) => Action::Delete,
-                    _ => Action::None,
-                };
-                match action {
-                    Action::Transfer => {
-                        if last != Action::Transfer {
-                            actions.push(Action::Transfer);
-                        }
-                    }
-                    Action::Delete => {
-                        actions.clear();
-                        actions.push(Action::Delete);
-                    }
-                    Action::None => {}
+        loop {
+            let done_before = walk.done.len();
+            let err = match self.walk_tree(walk) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+
+            if self.cli.reconnect_attempts == 0 || self.targets.iter().all(Target::alive) {
+                return Err(err);
+            }
+            // Don't loop forever on something that kills the connection every time
+            if walk.done.len() == done_before {
+                stalled += 1;
+                if stalled > self.cli.reconnect_attempts {
+                    return Err(err.context("Full sync keeps losing the connection"));
                 }
-                last = action;
+            } else {
+                stalled = 0;
             }
 
-            if let Some(final_action) = actions.last().copied() {
-                match final_action {
-                    Action::Transfer => self.transfer_element(&path)?,
-                    Action::Delete => self.delete_element(&path)?,
-                    Action::None => {}
+            warn!("Connection lost during full sync: {err:#}");
+            for i in 0..self.targets.len() {
+                let (before, rest) = self.targets.split_at_mut(i);
+                let t = &mut rest[0];
+                if t.alive() {
+                    continue;
+                }
+                // Roots on one login share a session again
+                match before.iter().find(|o| o.endpoint.same_login(&t.endpoint) && o.alive()) {
+                    Some(other) => t.rejoin(other)?,
+                    None => t.reconnect(&self.cli)?,
                 }
             }
+            info!("Resuming full sync ({} entries already done) …", walk.done.len());
         }
-
-        Ok(())
     }
 
-    fn transfer_element(&mut self, path: &Path) -> Result<()> {
-        if self.is_blacklisted(path) {
-            return Ok(());
-        }
+    fn walk_tree(&mut self, walk: &mut Walk) -> Result<()> {
+        // A directory stays at the front of the queue until all of its entries
+        // are handled, so a retry re-reads it and skips what is already done.
+        while let Some(dir) = walk.queue.front().cloned() {
+            for entry in fs::read_dir(&dir).with_context(|| format!("Reading {:?}", dir))? {
+                let entry = entry?;
+                let path = entry.path();
 
-        let meta = match fs::metadata(path) {
-            Ok(m) => m,
-            Err(_) => {
-                return self.delete_element(path);
-            }
-        };
+                if walk.done.contains(&path) || self.is_blacklisted(&path) {
+                    continue;
+                }
 
-        let rel = self.rel(path)?;
-        let remote = self.remote_root.join(&rel);
-        let mode: i32 = (meta.mode() & 0o777) as i32;
+                // Lexical, so entries below a followed link keep the link's name
+                let rel = path.strip_prefix(&self.local_root)?.to_path_buf();
+
+                let mut meta = entry.metadata()?;
+                if meta.file_type().is_symlink() {
+                    if !self.cli.allow_external_symlinks && self.external_symlink(&path) {
+                        warn!("skip: {} links outside {} (see --allow-external-symlinks)", path.display(), self.local_root.display());
+                    } else if !self.cli.follow_symlinks {
+                        // Recreate directory links rather than copying the tree twice
+                        if path.is_dir() {
+                            self.make_pending_dirs(walk, &rel)?;
+                            self.link_dir(&path, &rel)?;
+                        }
+                    } else if path.is_dir() && links_to_parent(&path, &dir) {
+                        warn!("skip: {} links to one of its parents", path.display());
+                    } else if let Ok(target) = fs::metadata(&path) {
+                        meta = target;
+                    }
+                }
 
-        if meta.is_dir() {
-            self.ensure_remote_dir(Some(&remote), mode)?;
-        } else if meta.is_file() {
-            if let Some(parent) = remote.parent() {
-                self.ensure_remote_dir(Some(parent), 0o755)?;
+                if meta.is_dir() {
+                    // With --dirs-first the full pass finds them all made
+                    let made = self.cli.dirs_first && walk.pass == Pass::Full;
+                    if self.cli.preserve_empty_dirs && !made {
+                        self.make_dirs(&rel, &meta)?;
+                    } else if !made {
+                        walk.pending_dirs.insert(rel.clone(), meta.clone());
+                    }
+                    if walk.pass == Pass::Permissions {
+                        self.fix_modes(&rel, &meta)?;
+                    }
+                    walk.queue.push_back(path.clone());
+                } else if meta.is_file() && !self.skip_file(&path, &meta) {
+                    let send = match walk.pass {
+                        Pass::Full if self.cli.checksum => !self.same_content(&path, &rel, &meta),
+                        _ => !self.up_to_date(&path, &rel, &meta),
+                    };
+                    if send {
+                        self.make_pending_dirs(walk, &rel)?;
+                        let inode = (self.cli.preserve_hardlinks && !self.cli.dry_run && meta.nlink() > 1)
+                            .then(|| (meta.dev(), meta.ino()));
+                        match inode.and_then(|i| walk.inodes.get(&i)) {
+                            Some(first) if self.hardlink(&first.clone(), &rel, &meta) => {}
+                            _ => {
+                                if let Err(e) = self.upload(&path, &rel, &meta) {
+                                    self.tolerate(e)?;
+                                    walk.done.insert(path);
+                                    continue;
+                                }
+                                if let Some(i) = inode {
+                                    walk.inodes.entry(i).or_insert_with(|| rel.clone());
+                                }
+                            }
+                        }
+                        self.remember_hash(&rel, &path, None);
+                        walk.sent.insert(rel.clone(), (meta.size(), meta.mtime()));
+                    } else {
+                        if walk.pass == Pass::Permissions {
+                            self.fix_modes(&rel, &meta)?;
+                        } else if walk.pass == Pass::Full {
+                            self.skipped(&path, &rel, meta.size());
+                        }
+                        // Already identical: as much ours as if we had uploaded it
+                        self.own(&rel);
+                    }
+                }
+                walk.done.insert(path);
             }
-            self.copy_file_to_remote(path, &remote, mode)?;
+            walk.queue.pop_front();
         }
         Ok(())
     }
 
-    fn delete_element(&mut self, path: &Path) -> Result<()> {
-        if self.is_blacklisted(path) {
-            return Ok(());
-        }
-        let rel = match self.rel(path) {
-            Ok(r) => r,
-            Err(_) => return Ok(()), // ignore paths outside local_root
-        };
-        let remote = self.remote_root.join(&rel);
-
-        // Try file unlink first, then rmdir. If directory not empty, attempt recursive.
-        if self.sftp.unlink(&remote).is_ok() {
-            eprintln!("remote: deleted file {}", remote.display());
-            return Ok(());
+    /// --dirs-first: create every directory below `dir` on the remote, before
+    /// the walk sends any file. Symlinked directories are left to the walk.
+    /// Returns how many there were.
+    fn make_skeleton(&mut self, dir: &Path) -> Result<usize> {
+        let mut made = 0;
+        for entry in fs::read_dir(dir).with_context(|| format!("Reading {:?}", dir))? {
+            let entry = entry?;
+            let path = entry.path();
+            if !entry.file_type()?.is_dir() || self.is_blacklisted(&path) {
+                continue;
+            }
+            let rel = path.strip_prefix(&self.local_root)?.to_path_buf();
+            self.make_dirs(&rel, &entry.metadata()?)?;
+            made += 1 + self.make_skeleton(&path)?;
         }
+        Ok(made)
+    }
 
-        // If it's a directory, try to remove recursively
-        if self.remote_is_dir(&remote)? {
-            self.remote_remove_dir_recursive(&remote)?;
-            eprintln!("remote: removed dir {}", remote.display());
+    /// Create the directories above `rel` that the walk has passed but not
+    /// created yet, top-down and with their local modes.
+    fn make_pending_dirs(&mut self, walk: &mut Walk, rel: &Path) -> Result<()> {
+        let mut missing: Vec<&Path> = rel.ancestors().skip(1).filter(|d| walk.pending_dirs.contains_key(*d)).collect();
+        missing.reverse();
+        for dir in missing {
+            self.make_dirs(dir, &walk.pending_dirs[dir])?;
+            walk.pending_dirs.remove(dir);
         }
-
         Ok(())
     }
 
-    fn copy_file_to_remote(&mut self, local: &Path, remote: &Path, mode: i32) -> Result<()> {
-        eprint!("sync: {} -> {}...", local.display(), remote.display());
-
-        let mut rf = self.sftp.create(remote)?;
-        let mut lf = File::open(local)?;
-        std::io::copy(&mut lf, &mut rf)?;
-
-        // Set mode
-        let stat = ssh2::FileStat {size: None, uid: None, atime: None, gid: None, mtime: None, perm: Some(mode as u32)};
-        let _ = self.sftp.setstat(remote, stat);
-
-        eprint!("DONE!\n");
-        Ok(())
+    /// --checksum: true if every target already has exactly the content of
+    /// `local`, whatever the timestamps say.
+    fn same_content(&self, local: &Path, rel: &Path, meta: &fs::Metadata) -> bool {
+        let mode = self.compare_mode(rel, CompareMode::Checksum);
+        let same = self
+            .targets
+            .iter()
+            .all(|t| t.matches(&t.remote_path(rel), local, meta, mode));
+        if same {
+            debug!("skip: {} (same content on the remote)", rel.display());
+        }
+        same
     }
 
-    fn ensure_remote_dir(&self, remote_dir: Option<&Path>, mode: i32) -> Result<()> {
-        let mut built = PathBuf::new();
+    /// True if every target already holds a copy of `local` matching per --compare-mode.
+    fn up_to_date(&self, local: &Path, rel: &Path, meta: &fs::Metadata) -> bool {
+        let mode = self.compare_mode(rel, self.cli.compare_mode);
+        self.targets
+            .iter()
+            .all(|t| t.matches(&t.remote_path(rel), local, meta, mode))
+    }
 
-        let r = remote_dir.unwrap_or(&self.remote_root);
-        for comp in r.components() {
-            built.push(comp.as_os_str());
-            if built.as_os_str().is_empty() {
-                continue;
-            }
+    /// `mode`, or just the mtime for --transform paths: the remote copy holds
+    /// the command's output, whose size and content differ from the local
+    /// file, but which was given its mtime.
+    fn compare_mode(&self, rel: &Path, mode: CompareMode) -> CompareMode {
+        if transform::find(&self.cli.transform, rel).is_some() { CompareMode::Mtime } else { mode }
+    }
 
-            match self.sftp.mkdir(&built, mode) {
-                Ok(_) => {}
-                Err(e) => {
-                    // If it already exists (race), ignore
-                    if !self.remote_exists(&built)? {
-                        return Err(e).with_context(|| format!("mkdir {:?}", built));
+    /// setstat the remote copies of `rel` whose mode differs from the local one.
+    fn fix_modes(&self, rel: &Path, meta: &fs::Metadata) -> Result<()> {
+        for t in &self.targets {
+            let remote = t.remote_path(rel);
+            let mode = t.masked(self.mode_for(meta));
+            match t.mode(&remote)? {
+                Some(current) if current != mode => {
+                    if self.cli.dry_run {
+                        info!("dry-run: would chmod {} {:o} -> {:o}", t.display(&remote), current, mode);
+                    } else {
+                        t.set_mode(&remote, mode)?;
+                        info!("chmod: {} {:o} -> {:o}", t.display(&remote), current, mode);
                     }
                 }
+                _ => {}
             }
         }
         Ok(())
     }
 
-    fn remote_exists(&self, remote: &Path) -> Result<bool> {
-        match self.sftp.stat(remote) {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
-        }
-    }
-
-    fn remote_is_dir(&mut self, remote: &Path) -> Result<bool> {
-        match self.sftp.stat(remote) {
-            Ok(stat) => Ok(stat.is_dir()),
-            Err(_) => Ok(false),
+    /// Apply exactly what git records between `rev` and HEAD: uploads for
+    /// added/modified files, deletes, and remote renames.
+    fn sync_since(&mut self, rev: &str) -> Result<()> {
+        let changes = git::changes_since(&self.local_root, rev)?;
+        info!("Syncing {} changes since {rev} …", changes.len());
+        for change in changes {
+            match change {
+                GitChange::Upload(rel) => self.transfer_element(&self.local_root.join(rel))?,
+                GitChange::Delete(rel) => self.delete_element(&self.local_root.join(rel))?,
+                GitChange::Rename(from, to) => self.rename_element(&from, &to)?,
+            }
         }
+        info!("Sync since {rev} complete.");
+        Ok(())
     }
 
-    fn remote_remove_dir_recursive(&mut self, remote: &Path) -> Result<()> {
-        // List entries; if readdir fails, try rmdir as a last resort
-        let entries = match self.sftp.readdir(remote) {
-            Ok(v) => v,
-            Err(_) => {
-                let _ = self.sftp.rmdir(remote);
-                return Ok(());
+    /// Rename `from` to `to` on every target, then upload `to` if its content
+    /// changed as well. Falls back to delete + upload where renaming fails.
+    fn rename_element(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let path = self.local_root.join(to);
+        let meta = match fs::metadata(&path) {
+            Ok(m) if m.is_file() && !self.cli.dry_run && !self.is_blacklisted(&path) && !self.skip_file(&path, &m) => m,
+            _ => {
+                self.delete_element(&self.local_root.join(from))?;
+                return self.transfer_element(&path);
             }
         };
 
-        for (child, stat) in entries {
-            if let Some(name) = child.file_name() {
-                if name == "." || name == ".." {
-                    continue;
-                }
+        for t in &mut self.targets {
+            let (old, new) = (t.remote_path(from), t.remote_path(to));
+            if let Some(parent) = new.parent() {
+                t.ensure_dir(Some(parent), 0o755)?;
             }
-            if stat.is_dir() {
-                self.remote_remove_dir_recursive(&child)?;
-            } else {
-                let _ = self.sftp.unlink(&child);
+            match t.rename(&old, &new) {
+                Ok(()) => info!("remote: renamed {} -> {}", t.display(&old), t.display(&new)),
+                Err(e) => {
+                    warn!("{e:#}; uploading instead");
+                    t.delete(&old, &|_| true)?;
+                }
             }
         }
-        let _ = self.sftp.rmdir(remote);
+        if !self.up_to_date(&path, to, &meta) {
+            self.upload(&path, to, &meta)?;
+        }
         Ok(())
     }
 
-    fn rel(&self, path: &Path) -> Result<PathBuf> {
-        let canon = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
-        canon
-            .strip_prefix(&self.local_root)
-            .map(|p| p.to_path_buf())
-            .with_context(|| format!("Path {:?} is outside project root {:?}", path, self.local_root))
+    /// Re-walk the tree and re-send whatever drifted, e.g. after missed events.
+    fn reconcile(&mut self) -> Result<()> {
+        let _span = info_span!("reconcile").entered();
+        info!("Reconciling …");
+        let sent = self.transfer_all(Pass::Changed)?;
+        info!("Reconcile complete ({} files re-sent).", sent.len());
+        Ok(())
     }
 
-    fn is_blacklisted(&self, path: &Path) -> bool {
-        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-            if self.blacklist_names.contains(name) {
-                return true;
-            }
-        }
-        for blk in &self.blacklist {
-            if path.starts_with(blk) {
-                return true;
+    /// `initial` is a background initial sync; events queue up until it finishes.
+    /// `control` carries calls from the --control-socket, answered in between batches.
+    /// `watcher` is moved to the new root if --follow-root-swap sees it swapped.
+    fn dispatcher(
+        &mut self,
+        m_rx: Receiver<Event>,
+        mut initial: Option<JoinHandle<Result<Sent>>>,
+        control: Option<Receiver<control::Request>>,
+        mut watcher: Option<RecommendedWatcher>,
+    ) -> Result<()> {
+        let mut last_tick = Instant::now();
+        let mut last_root_check = Instant::now();
+        let mut root_gone = None;
+        let mut last_reconcile = Instant::now();
+        let mut events = VecDeque::new();
+
+        // SIGUSR1 toggles pausing: events keep queuing but nothing is uploaded
+        let toggle = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGUSR1, Arc::clone(&toggle)).context("Installing SIGUSR1 handler")?;
+        let mut paused = false;
+        // Set when the queue overflowed --max-queue and was dropped
+        let mut rescan = false;
+        let mut last_event = Instant::now();
+        // No more events will come; exit once the queue is worked off
+        let mut closed = false;
+
+        loop {
+            while !closed {
+                match m_rx.try_recv() {
+                    Ok(ev) => {
+                        events.push_back(ev);
+                        last_event = Instant::now();
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        info!("Event source closed; exiting after {} queued events.", events.len());
+                        closed = true;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            while let Some(c_rx) = &control
+                && let Ok(req) = c_rx.try_recv()
+            {
+                if matches!(req.call, Call::Sync(_)) {
+                    last_event = Instant::now();
+                }
+                let res = self.control(req.call, &mut events, &mut paused, &mut rescan);
+                let _ = req.reply.send(res);
+            }
+
+            if let Some(max) = self.cli.max_queue
+                && events.len() > max
+            {
+                warn!("More than {max} events queued; dropping them in favour of one rescan.");
+                events.clear();
+                rescan = true;
+            }
+
+            if toggle.swap(false, Ordering::Relaxed) {
+                paused = !paused;
+                if paused {
+                    info!("Paused ({} events queued); send SIGUSR1 again to resume.", events.len());
+                } else {
+                    info!("Resumed; flushing {} queued events.", events.len());
+                }
+            }
+
+            if let Some(h) = initial.take_if(|h| !paused && h.is_finished()) {
+                let sent = h.join().map_err(|_| anyhow!("Initial sync thread panicked"))??;
+                info!("Initial sync complete; flushing {} queued events.", events.len());
+                sent.keys().for_each(|rel| self.own(rel));
+                self.last_synced.extend(sent.iter().map(|(k, v)| (k.clone(), *v)));
+                // Pushed by the other connection; the end of the sync is close enough
+                let now = SystemTime::now();
+                sent.keys().for_each(|rel| self.history.record(rel, now));
+                self.initial_sent = sent;
+                self.process_events(&mut events)?;
+                self.initial_sent.clear();
+                last_reconcile = Instant::now();
+            }
+
+            if !paused && initial.is_none() && last_tick.elapsed() >= self.debounce {
+                last_tick = Instant::now();
+                if rescan {
+                    // The rescan covers whatever queued up since the collapse
+                    rescan = false;
+                    events.clear();
+                    self.reconcile()?;
+                    last_reconcile = Instant::now();
+                } else {
+                    self.process_events(&mut events)?;
+                    // Everything else went with it. Counted, so every flag is reset
+                    if self.targets.iter().filter(|t| t.take_root_recreated()).count() > 0 {
+                        info!("Re-syncing into the recreated remote directory …");
+                        self.reconcile()?;
+                        last_reconcile = Instant::now();
+                    }
+                }
+            }
+
+            if let Some(w) = &mut watcher
+                && self.cli.follow_root_swap
+                && initial.is_none()
+                && last_root_check.elapsed() >= ROOT_CHECK
+            {
+                last_root_check = Instant::now();
+                if self.follow_root(w, &mut root_gone)? {
+                    // Queued paths may belong to the old tree; the pass covers the new one
+                    events.clear();
+                    rescan = false;
+                    self.reconcile()?;
+                    last_reconcile = Instant::now();
+                }
+            }
+
+            // Runs inline, so passes can never overlap; the interval counts
+            // from the end of the previous pass.
+            if let Some(interval) = self.cli.reconcile_interval
+                && !paused
+                && initial.is_none()
+                && last_reconcile.elapsed() >= interval
+            {
+                self.reconcile()?;
+                last_reconcile = Instant::now();
+            }
+
+            if closed && events.is_empty() && !rescan && initial.is_none() {
+                return Ok(());
+            }
+
+            if let Some(idle) = self.cli.idle_exit
+                && events.is_empty()
+                && !rescan
+                && !paused
+                && initial.is_none()
+                && last_event.elapsed() >= idle
+            {
+                info!("No changes for {idle:?}; exiting.");
+                return Ok(());
+            }
+
+            // Keep CPU calm
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// --follow-root-swap: true if the local root path now names another
+    /// directory than the one watched, in which case `watcher` is moved over
+    /// to it. A root that is missing (say, midway through a swap) is waited
+    /// for up to ROOT_GONE, counted in `gone`.
+    fn follow_root(&mut self, watcher: &mut RecommendedWatcher, gone: &mut Option<Instant>) -> Result<bool> {
+        let path = self.cli.dir.clone().unwrap_or_else(|| self.local_root.clone());
+        let root = match self.cli.local_root() {
+            Ok(root) => root,
+            Err(e) => {
+                let since = *gone.get_or_insert_with(|| {
+                    warn!("Local root {} is gone; waiting for it to come back …", path.display());
+                    Instant::now()
+                });
+                if since.elapsed() >= ROOT_GONE {
+                    return Err(e.context(format!("Local root gone for {}s (--follow-root-swap)", ROOT_GONE.as_secs())));
+                }
+                return Ok(false);
+            }
+        };
+        *gone = None;
+        let id = fs::metadata(&root).map(|m| (m.dev(), m.ino()))?;
+        if id == self.root_id && root == self.local_root {
+            return Ok(false);
+        }
+
+        warn!("Local root {} was swapped for another directory; following it to {}", path.display(), root.display());
+        let _ = watcher.unwatch(&self.local_root);
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .with_context(|| format!("Starting watch on {}", root.display()))?;
+        if self.cli.gitignore {
+            self.gitignore = Some(GitIgnore::load(&root)?);
+        }
+        if self.cli.use_dockerignore {
+            self.dockerignore = Some(DockerIgnore::load(&root)?);
+        }
+        self.local_root = root;
+        self.root_id = id;
+        info!("Watching {} again; reconciling against it …", self.local_root.display());
+        Ok(true)
+    }
+
+    /// --max-errors: log a failed transfer and carry on, until that happened
+    /// too often. Without the flag, or once a connection is gone (which the
+    /// reconnect logic handles), the error stands.
+    fn tolerate(&mut self, e: anyhow::Error) -> Result<()> {
+        let Some(max) = self.cli.max_errors else { return Err(e) };
+        if !self.targets.iter().all(Target::alive) {
+            return Err(e);
+        }
+        self.errors += 1;
+        if self.errors >= max.get() {
+            return Err(e.context(format!("Giving up after {} failed transfers (--max-errors)", self.errors)));
+        }
+        error!("{e:#} ({} of {max} failures allowed)", self.errors);
+        Ok(())
+    }
+
+    /// Answer one --control-socket call with its JSON-RPC result.
+    fn control(&mut self, call: Call, events: &mut VecDeque<Event>, paused: &mut bool, rescan: &mut bool) -> Result<Value, String> {
+        match call {
+            Call::Status => Ok(json!({
+                "paused": *paused,
+                "queued": events.len(),
+                "rescan_pending": *rescan,
+                "synced": self.synced.len(),
+                "history": self.history.len(),
+                "targets": self.targets.iter().map(|t| t.display(&t.root)).collect::<Vec<_>>(),
+            })),
+            Call::LastSynced(path) => self.last_synced(&path),
+            Call::Rescan => {
+                info!("Rescan requested over the control socket.");
+                *rescan = true;
+                Ok(json!({ "scheduled": true }))
+            }
+            Call::Pause | Call::Resume => {
+                *paused = matches!(call, Call::Pause);
+                info!("{} over the control socket ({} events queued).", if *paused { "Paused" } else { "Resumed" }, events.len());
+                Ok(json!({ "paused": *paused }))
+            }
+            Call::BlacklistAdd(entry) => {
+                info!("Blacklisting {entry} (control socket)");
+                self.blacklist.push(PathBuf::from(&entry));
+                self.blacklist_names = blacklist_names(&self.blacklist);
+                Ok(json!({ "blacklist": self.blacklist }))
+            }
+            Call::BlacklistRemove(entry) => {
+                let before = self.blacklist.len();
+                self.blacklist.retain(|b| *b != Path::new(&entry));
+                if self.blacklist.len() == before {
+                    return Err(format!("{entry:?} is not blacklisted"));
+                }
+                info!("No longer blacklisting {entry} (control socket)");
+                self.blacklist_names = blacklist_names(&self.blacklist);
+                Ok(json!({ "blacklist": self.blacklist }))
+            }
+            Call::Sync(path) => {
+                let full = self.local_root.join(path);
+                let rel = self.rel(&full).map_err(|e| format!("{e:#}"))?;
+                events.push_back(Event::new(EventKind::Modify(ModifyKind::Any)).add_path(full));
+                Ok(json!({ "queued": rel }))
+            }
+            Call::Deploy => Err("deploy only works with --standby; use rescan".into()),
+        }
+    }
+
+    /// The `last_synced` answer for `path`: when it was last pushed, in
+    /// seconds since the epoch and ago, or nulls if not in the history.
+    fn last_synced(&self, path: &Path) -> Result<Value, String> {
+        let rel = self.rel(&self.local_root.join(path)).map_err(|e| format!("{e:#}"))?;
+        let at = self.history.get(&rel);
+        let secs = |d: Duration| d.as_secs_f64();
+        Ok(json!({
+            "path": rel,
+            "synced_at": at.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(secs),
+            "ago_secs": at.and_then(|t| t.elapsed().ok()).map(secs),
+        }))
+    }
+
+    /// --standby: connected, but nothing is synced until SIGUSR1 or a
+    /// `deploy` call on the control socket asks for a full sync. In between,
+    /// keepalives hold the connections open.
+    fn standby(&mut self) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        if let Some(path) = &self.cli.control_socket {
+            control::serve(path, tx.clone())?;
+        }
+        let mut signals = Signals::new([SIGUSR1]).context("Installing SIGUSR1 handler")?;
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                // Nobody waits for the answer to a signal
+                let (reply, _) = mpsc::channel();
+                if tx.send(control::Request { call: Call::Deploy, reply }).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let keepalive = Duration::from_secs(KEEPALIVE_SECS.into());
+        let mut deploys = 0;
+        info!("Standing by; send SIGUSR1 or call deploy on the control socket to sync.");
+        loop {
+            let req = match rx.recv_timeout(keepalive) {
+                Ok(req) => req,
+                Err(RecvTimeoutError::Timeout) => {
+                    for t in &self.targets {
+                        if let Err(e) = t.keepalive() {
+                            debug!("{}: {e:#}", t.endpoint.host);
+                        }
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            let res = match req.call {
+                Call::Status => Ok(json!({
+                    "standby": true,
+                    "deploys": deploys,
+                    "targets": self.targets.iter().map(|t| t.display(&t.root)).collect::<Vec<_>>(),
+                })),
+                Call::Deploy => {
+                    deploys += 1;
+                    self.deploy().map_err(|e| {
+                        error!("Deploy failed: {e:#}; standing by.");
+                        format!("{e:#}")
+                    })
+                }
+                Call::LastSynced(path) => self.last_synced(&path),
+                _ => Err("only status, last_synced and deploy work with --standby".into()),
+            };
+            let _ = req.reply.send(res);
+        }
+    }
+
+    /// One full sync for --standby, over connections checked first, since
+    /// they may have died while idle.
+    fn deploy(&mut self) -> Result<Value> {
+        let _span = info_span!("deploy").entered();
+        for t in &mut self.targets {
+            if !t.alive() {
+                warn!("{}: connection lost while standing by; reconnecting", t.endpoint.host);
+                t.reconnect(&self.cli)?;
+            }
+        }
+        info!("Deploying …");
+        let started = Instant::now();
+        let errors = self.errors;
+        let sent = self.initial_sync()?;
+        if self.cli.final_digest {
+            self.final_digest()?;
+        }
+        self.after_initial_sync(errors)?;
+        let secs = started.elapsed().as_secs_f64();
+        info!("Deploy complete: {} files in {}; standing by.", sent.len(), human_duration(secs));
+        Ok(json!({ "files": sent.len(), "secs": secs }))
+    }
+
+    /// Coalesce many events per path into a minimal action list.
+    fn process_events(&mut self, events: &mut VecDeque<Event>) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let _span = info_span!("batch", events = events.len()).entered();
+
+        let mut per_path: HashMap<PathBuf, Vec<EventKind>> = HashMap::new();
+
+        while let Some(e) = events.pop_front() {
+            for p in e.paths {
+                // Absolutize to compare reliably; ignore errors quietly
+                let full = self.absolute(&p);
+                per_path.entry(full).or_default().push(e.kind);
+            }
+        }
+
+        // Parents before children, so new directories exist before their contents
+        let mut per_path: Vec<_> = per_path.into_iter().collect();
+        per_path.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut deletes = Vec::new();
+        let mut transfers = 0;
+
+        let dump = self.cli.dump_events;
+        for (path, kinds) in per_path {
+            // --dump-events: what became of each path, and why
+            let seen = if dump { format!("{kinds:?}") } else { String::new() };
+            let decide = |what: &str| {
+                if dump {
+                    info!("decide: {} {seen} -> {what}", path.display());
+                }
+            };
+
+            if let Some(rule) = self.blacklist_rule(&path) {
+                debug!("skip: {} (blacklisted by '{rule}')", path.display());
+                decide(&format!("ignore (blacklisted by '{rule}')"));
+                continue;
+            }
+            // Editors like to drop temp files outside the tree (e.g. in /tmp);
+            // those are never worth failing a batch over.
+            let rel = match self.rel(&path) {
+                Ok(rel) => rel,
+                Err(e) => {
+                    decide("ignore (outside the local root)");
+                    debug!("skip: {e:#}");
+                    continue;
+                }
+            };
+            if self.no_watch.matches(&rel) {
+                decide("ignore (--no-watch-path)");
+                continue;
+            }
+            if !self.watch_only.is_empty() && !self.watch_only.matches(&rel) {
+                decide("ignore (not matched by --watch-only-match)");
+                continue;
+            }
+
+            let mut actions: Vec<Action> = Vec::new();
+            let mut last = Action::None;
+
+            for k in kinds {
+                let action = match k {
+                    EventKind::Create(_) | EventKind::Modify(_) => Action::Transfer,
+                    EventKind::Remove(_) => Action::Delete,
+                    _ => Action::None,
+                };
+                match action {
+                    Action::Transfer => {
+                        if last != Action::Transfer {
+                            actions.push(Action::Transfer);
+                        }
+                    }
+                    Action::Delete => {
+                        actions.clear();
+                        actions.push(Action::Delete);
+                    }
+                    Action::None => {}
+                }
+                last = action;
+            }
+
+            match actions.last().copied().unwrap_or(Action::None) {
+                // Gone again by now: a delete after all
+                Action::Transfer if fs::symlink_metadata(&path).is_err() => {
+                    decide("delete (no longer exists)");
+                    deletes.push(path);
+                }
+                Action::Transfer if self.below_watch_min_size(&path) => {
+                    decide("ignore (--watch-min-size)");
+                    debug!("skip: {} (smaller than --watch-min-size)", path.display());
+                }
+                // Stays queued, in path order, for the next flush
+                Action::Transfer if self.cli.max_batch_files.is_some_and(|max| transfers >= max.get()) => {
+                    decide("defer (--max-batch-files)");
+                    events.push_back(Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path));
+                }
+                Action::Transfer => {
+                    decide("transfer");
+                    transfers += 1;
+                    if let Err(e) = self.transfer_element(&path) {
+                        self.tolerate(e)?;
+                    }
+                }
+                Action::Delete => {
+                    decide("delete");
+                    deletes.push(path);
+                }
+                Action::None => decide("nothing"),
+            }
+        }
+
+        deepest_first(&mut deletes);
+        if deletes.len() > self.cli.confirm_over
+            && !self.cli.yes
+            && !self.cli.dry_run
+            && !confirm(&format!("This batch would delete {} paths", deletes.len()))?
+        {
+            warn!("keep: {} deleted paths (large delete not confirmed)", deletes.len());
+            deletes.clear();
+        }
+        for path in deletes {
+            if let Err(e) = self.delete_element(&path) {
+                self.tolerate(e)?;
+            }
+        }
+
+        self.save_owned();
+        if !events.is_empty() {
+            info!("Deferred {} transfers to the next batch (--max-batch-files)", events.len());
+        }
+        Ok(())
+    }
+
+    fn below_watch_min_size(&self, path: &Path) -> bool {
+        self.cli
+            .watch_min_size
+            .is_some_and(|min| fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() < min))
+    }
+
+    fn transfer_element(&mut self, path: &Path) -> Result<()> {
+        if self.is_blacklisted(path) {
+            return Ok(());
+        }
+
+        let meta = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => {
+                return self.delete_element(path);
+            }
+        };
+
+        if !self.cli.allow_external_symlinks && self.external_symlink(path) {
+            warn!("skip: {} links outside {} (see --allow-external-symlinks)", path.display(), self.local_root.display());
+            return Ok(());
+        }
+
+        let rel = self.rel(path)?;
+
+        if meta.is_dir() && !self.cli.follow_symlinks && fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+            self.link_dir(path, &rel)?;
+        } else if meta.is_dir() {
+            self.make_dirs(&rel, &meta)?;
+        } else if meta.is_file() && !self.skip_file(path, &meta) {
+            if self.initial_sent.get(&rel) == Some(&(meta.size(), meta.mtime())) {
+                return Ok(());
+            }
+            // A stat per event (a full read with checksum). A match against
+            // the very size and mtime we uploaded proves nothing, though:
+            // a second same-size save within that second looks the same
+            let ours = self.cli.compare_mode != CompareMode::Checksum
+                && self.last_synced.get(&rel) == Some(&(meta.size(), meta.mtime()));
+            if !ours && self.up_to_date(path, &rel, &meta) {
+                info!("skip: {} (already up to date on the remote)", path.display());
+                return Ok(());
+            }
+            // Tools that only bump the mtime shouldn't cause a re-upload
+            let hash = if self.cli.skip_touch_only { sha256_file(path).ok() } else { None };
+            if hash.is_some() && self.hashes.get(&rel) == hash.as_ref() {
+                info!("skip: {} (touched, content unchanged)", path.display());
+                return Ok(());
+            }
+            self.upload(path, &rel, &meta)?;
+            self.remember_hash(&rel, path, hash);
+        }
+        Ok(())
+    }
+
+    /// With --skip-touch-only, record the content hash of what was just uploaded.
+    fn remember_hash(&mut self, rel: &Path, path: &Path, hash: Option<[u8; 32]>) {
+        if !self.cli.skip_touch_only || self.cli.dry_run {
+            return;
+        }
+        match hash.map_or_else(|| sha256_file(path), Ok) {
+            Ok(h) => {
+                self.hashes.insert(rel.to_path_buf(), h);
+            }
+            Err(e) => warn!("Could not hash {}: {e}", path.display()),
+        }
+    }
+
+    /// Mirror a symlink to a directory as a symlink with the same target.
+    fn link_dir(&mut self, path: &Path, rel: &Path) -> Result<()> {
+        let target = fs::read_link(path).with_context(|| format!("Reading link {}", path.display()))?;
+        if self.cli.dry_run {
+            if self.targets.iter().any(|t| t.read_link(&t.remote_path(rel)).as_ref() != Some(&target)) {
+                self.report(Change::Added, rel);
+            }
+            return Ok(());
+        }
+        for t in &self.targets {
+            let remote = t.remote_path(rel);
+            if let Some(parent) = remote.parent() {
+                t.ensure_dir(Some(parent), 0o755)?;
+            }
+            t.symlink(&remote, &target)?;
+        }
+        self.own(rel);
+        Ok(())
+    }
+
+    /// Record `rel` as synced, and where it went on every target as put
+    /// there by rmote.
+    fn own(&mut self, rel: &Path) {
+        for t in &mut self.targets {
+            let remote = t.remote_path(rel);
+            t.own(&remote);
+        }
+        self.synced.insert(rel.to_path_buf());
+    }
+
+    /// Write down what every target owns. A failure only warns: the next
+    /// run then deletes less, never more.
+    fn save_owned(&mut self) {
+        for t in &mut self.targets {
+            if let Err(e) = t.save_owned() {
+                warn!("Saving what rmote owns on {}: {e:#}", t.endpoint.host);
+            }
+        }
+    }
+
+    /// Create the remote counterpart of a local directory on every target.
+    fn make_dirs(&mut self, rel: &Path, meta: &fs::Metadata) -> Result<()> {
+        if self.cli.dry_run {
+            return Ok(());
+        }
+        let local = self.local_root.join(rel);
+        if let Some(other) = self.case_collision(&local) {
+            warn!("skip: {} (same remote name as {} with --remote-case)", local.display(), other.display());
+            return Ok(());
+        }
+        let mode = self.mode_for(meta);
+        for t in &mut self.targets {
+            let remote = t.remote_path(rel);
+            retry::run(&self.cli, t, "mkdir", |t| t.ensure_dir(Some(&remote), mode))?;
+        }
+        self.mirror_acl(&local, rel);
+        self.own(rel);
+        Ok(())
+    }
+
+    /// A sibling of `path`, or of one of its parents below the local root,
+    /// whose name --remote-case turns into the same one.
+    fn case_collision(&self, path: &Path) -> Option<PathBuf> {
+        let case = self.cli.remote_case;
+        if case == RemoteCase::Preserve {
+            return None;
+        }
+        path.ancestors()
+            .take_while(|p| p.starts_with(&self.local_root) && *p != self.local_root)
+            .find_map(|p| {
+                let (dir, name) = (p.parent()?, p.file_name()?);
+                let remote = case.apply(name);
+                fs::read_dir(dir)
+                    .ok()?
+                    .flatten()
+                    .find(|e| e.file_name() != name && case.apply(&e.file_name()) == remote)
+                    .map(|e| e.path())
+            })
+    }
+
+    /// --preserve-hardlinks: link `rel` to the already uploaded `first` on
+    /// every target instead of sending the same content again. Returns false,
+    /// after a warning, if that failed and `rel` should be uploaded instead.
+    fn hardlink(&mut self, first: &Path, rel: &Path, meta: &fs::Metadata) -> bool {
+        let res = self.targets.iter().try_for_each(|t| t.hardlink(&t.remote_path(first), &t.remote_path(rel)));
+        if let Err(e) = res {
+            warn!("Hard link failed, uploading instead: {e:#}");
+            return false;
+        }
+        info!("link: {} -> {}", rel.display(), first.display());
+        self.own(rel);
+        self.last_synced.insert(rel.to_path_buf(), (meta.size(), meta.mtime()));
+        self.history.record(rel, SystemTime::now());
+        true
+    }
+
+    /// Copy one local file to every target, creating parent dirs as needed.
+    fn upload(&mut self, local: &Path, rel: &Path, meta: &fs::Metadata) -> Result<()> {
+        // Two local files for one remote path: the one already named so, or
+        // else the first one seen, wins
+        let root = &self.local_root;
+        if let Some(other) = self.rename_claims.clash(&self.cli.rename, rel, |p| root.join(p).exists()) {
+            warn!("skip: {} (--rename gives it the same remote name as {})", rel.display(), other.display());
+            self.skipped(local, rel, meta.size());
+            return Ok(());
+        }
+        if let Some(other) = self.case_collision(local) {
+            warn!("skip: {} (same remote name as {} with --remote-case)", local.display(), other.display());
+            self.skipped(local, rel, meta.size());
+            return Ok(());
+        }
+
+        if self.cli.dry_run {
+            let mode = self.compare_mode(rel, self.cli.compare_mode);
+            let stale = self
+                .targets
+                .iter()
+                .filter(|t| !t.matches(&t.remote_path(rel), local, meta, mode))
+                .count();
+            if stale > 0 {
+                let exists = self.targets.iter().any(|t| t.exists(&t.remote_path(rel)).unwrap_or(false));
+                self.report(if exists { Change::Modified } else { Change::Added }, rel);
+                self.dry_run_files += 1;
+                self.dry_run_bytes += meta.size() * stale as u64;
+            }
+            return Ok(());
+        }
+
+        // --transform: what gets uploaded is the command's output
+        let transformed = match transform::find(&self.cli.transform, rel) {
+            Some(tf) => match meta.modified().map_err(Into::into).and_then(|mtime| tf.apply(local, mtime)) {
+                Ok(out) => Some(out),
+                Err(e) => {
+                    warn!("skip: {} (--transform failed: {e:#})", rel.display());
+                    self.skipped(local, rel, meta.size());
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        let src = transformed.as_ref().map_or(local, |out| out.path());
+        let size = match &transformed {
+            Some(out) => fs::metadata(out.path())?.size(),
+            None => meta.size(),
+        };
+
+        let mode = self.mode_for(meta);
+        let mut failed = None;
+        for t in &mut self.targets {
+            let remote = t.remote_path(rel);
+            if let Some(parent) = remote.parent()
+                && let Err(e) = retry::run(&self.cli, t, "mkdir", |t| t.ensure_dir(Some(parent), 0o755))
+            {
+                note_failure(&mut failed, &t.display(&remote), e);
+                continue;
+            }
+            if let Some(&(size, mtime)) = self.last_synced.get(rel)
+                && (self.cli.conflict != Conflict::LocalWins || self.cli.conflict_command.is_some())
+                && let Some(state) = t.file_state(&remote)
+                && state != (size, mtime)
+                && !resolve_conflict(&self.cli, t, &remote, meta.mtime(), state.1)?
+            {
+                let shown = t.display(&remote);
+                let tr = Transfer { local, rel, remote: &shown, size: meta.size() };
+                for o in &mut self.observers {
+                    o.on_file_skipped(&tr);
+                }
+                continue;
+            }
+            let shown = t.display(&remote);
+            let tr = Transfer { local, rel, remote: &shown, size };
+            for o in &mut self.observers {
+                o.on_file_start(&tr);
+            }
+            let mut sent = 0;
+            let res = retry::run(&self.cli, t, &shown, |t| {
+                sent = 0;
+                let mut progress = |n| {
+                    sent += n;
+                    for o in &mut self.observers {
+                        o.on_file_progress(&tr, sent);
+                    }
+                };
+                if transformed.is_none() && self.append.matches(rel) {
+                    t.append_file(local, &remote, mode, &mut progress)
+                } else {
+                    let tmp = self.cli.atomic.then(|| self.temp_names.next(&remote));
+                    t.copy_file(src, &remote, tmp.as_deref(), mode, &mut progress)
+                }
+            });
+            for o in &mut self.observers {
+                match &res {
+                    Ok(()) => o.on_file_complete(&tr, sent),
+                    Err(e) => o.on_error(&tr, e),
+                }
+            }
+            match res {
+                Ok(()) => t.own(&remote),
+                Err(e) => note_failure(&mut failed, &shown, e),
+            }
+        }
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        self.mirror_acl(local, rel);
+        self.synced.insert(rel.to_path_buf());
+        self.last_synced.insert(rel.to_path_buf(), (size, meta.mtime()));
+        self.history.record(rel, SystemTime::now());
+        Ok(())
+    }
+
+    /// With --preserve-acls, give `rel` on every target that has `setfacl`
+    /// the ACL of `local`. Failures only warn, as the data is already there.
+    fn mirror_acl(&self, local: &Path, rel: &Path) {
+        if !self.acls {
+            return;
+        }
+        let acl = match acl::read(local) {
+            Ok(Some(acl)) => acl,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("acl: {e:#}");
+                return;
+            }
+        };
+        for t in self.targets.iter().filter(|t| t.acls) {
+            if let Err(e) = t.set_acl(&t.remote_path(rel), &acl) {
+                warn!("acl: {e:#}");
+            }
+        }
+    }
+
+    /// Total size of the files below `dir` that a full sync would send.
+    fn local_bytes(&self, dir: &Path) -> u64 {
+        let Ok(entries) = fs::read_dir(dir) else { return 0 };
+        let mut total = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if self.is_blacklisted(&path) {
+                continue;
+            }
+            match entry.metadata() {
+                Ok(m) if m.is_dir() => total += self.local_bytes(&path),
+                Ok(m) if m.is_file() && self.skip_reason(&path, &m).is_none() => total += m.size(),
+                _ => {}
+            }
+        }
+        total
+    }
+
+    /// Remote mode for a local entry. Setuid/setgid/sticky are dropped unless
+    /// explicitly requested. With --exec-bit-only, only the owner's exec bit
+    /// picks between two fixed modes; directories always have it.
+    fn mode_for(&self, meta: &fs::Metadata) -> i32 {
+        remote_mode(&self.cli, meta.mode())
+    }
+
+    /// Per-file filters that go beyond the blacklist. Logs why a file is skipped.
+    fn skip_file(&self, path: &Path, meta: &fs::Metadata) -> bool {
+        let Some(why) = self.skip_reason(path, meta) else { return false };
+        // Most of a tree can fall outside --ext; that would drown the log
+        if why == NOT_IN_EXT {
+            debug!("skip: {} ({why})", path.display());
+        } else {
+            info!("skip: {} ({why})", path.display());
+        }
+        true
+    }
+
+    /// Why `skip_file` skips `path`, without logging it.
+    fn skip_reason(&self, path: &Path, meta: &fs::Metadata) -> Option<&'static str> {
+        if self.cli.skip_empty
+            && meta.len() == 0
+            && !self.rel(path).is_ok_and(|rel| self.keep_empty.matches(&rel))
+        {
+            return Some("empty, see --skip-empty");
+        }
+        if !self.cli.ext.is_empty() {
+            let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+            if !ext.is_some_and(|e| self.cli.ext.contains(&e)) {
+                return Some(NOT_IN_EXT);
+            }
+        }
+        if self.cli.text_only || self.cli.binary_only {
+            match looks_binary(path) {
+                Ok(binary) if binary == self.cli.text_only => {
+                    return Some(if binary { "binary file" } else { "text file" });
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Could not sniff {}: {e}", path.display()),
+            }
+        }
+        None
+    }
+
+    /// Tell the observers that none of the targets gets `rel` after all.
+    fn skipped(&mut self, local: &Path, rel: &Path, size: u64) {
+        for t in &self.targets {
+            let shown = t.display(&t.remote_path(rel));
+            let tr = Transfer { local, rel, remote: &shown, size };
+            for o in &mut self.observers {
+                o.on_file_skipped(&tr);
+            }
+        }
+    }
+
+    fn delete_element(&mut self, path: &Path) -> Result<()> {
+        if self.is_blacklisted(path) {
+            return Ok(());
+        }
+        let rel = match self.rel(path) {
+            Ok(r) => r,
+            Err(_) => return Ok(()), // ignore paths outside local_root
+        };
+        // Its remote name went to another local file, whose copy that is
+        let root = &self.local_root;
+        if let Some(other) = self.rename_claims.taken(&self.cli.rename, &rel, |p| root.join(p).exists()) {
+            info!("keep: {} (--rename gives {} the same remote name)", rel.display(), other.display());
+            return Ok(());
+        }
+        if self.cli.dry_run {
+            if self.targets.iter().any(|t| {
+                let remote = t.remote_path(&rel);
+                !kept(&self.keep, t, &remote) && t.exists(&remote).unwrap_or(false)
+            }) {
+                self.report(Change::Deleted, &rel);
+            }
+            return Ok(());
+        }
+
+        // A directory counts if anything below it was synced
+        if !self.cli.prune_all && !self.targets.iter().any(|t| t.owns_under(&t.remote_path(&rel))) {
+            info!("keep: {} (not synced by rmote; see --prune-all)", rel.display());
+            return Ok(());
+        }
+
+        let mut failed = None;
+        for t in &mut self.targets {
+            let remote = t.remote_path(&rel);
+            if kept(&self.keep, t, &remote) {
+                info!("keep: {} (--keep)", t.display(&remote));
+                continue;
+            }
+            let owned = deletable(t, &self.keep, self.cli.prune_all, &remote);
+            if !confirm_delete(&self.cli, t, &remote, &owned)? {
+                warn!("keep: {} (large delete not confirmed)", t.display(&remote));
+                continue;
+            }
+            let shown = t.display(&remote);
+            match retry::run(&self.cli, t, &shown, |t| t.delete(&remote, &owned)) {
+                Ok(()) => t.disown(&remote),
+                Err(e) => note_failure(&mut failed, &shown, e),
+            }
+        }
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        self.synced.retain(|p| !p.starts_with(&rel));
+        for o in &mut self.observers {
+            o.on_deleted(&rel);
+        }
+        Ok(())
+    }
+
+    /// What a dry run would have done to `rel`.
+    fn report(&self, change: Change, rel: &Path) {
+        if self.cli.porcelain {
+            println!("{} {}", change.code(), rel.display());
+        } else {
+            info!("dry-run: would {} {}", change.verb(), rel.display());
+        }
+    }
+
+    fn rel(&self, path: &Path) -> Result<PathBuf> {
+        self.absolute(path)
+            .strip_prefix(&self.local_root)
+            .map(|p| p.to_path_buf())
+            .with_context(|| format!("Path {:?} is outside project root {:?}", path, self.local_root))
+    }
+
+    /// `path` made absolute: canonicalized, or only cleaned up lexically with
+    /// --fast-paths.
+    fn absolute(&self, path: &Path) -> PathBuf {
+        if self.cli.fast_paths { normalize(path) } else { absolutize(path) }
+    }
+
+    /// A symlink whose resolved target lies outside the local root.
+    fn external_symlink(&self, path: &Path) -> bool {
+        fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+            && fs::canonicalize(path).is_ok_and(|t| !t.starts_with(&self.local_root))
+    }
+
+    fn is_blacklisted(&self, path: &Path) -> bool {
+        let Some(rule) = self.blacklist_rule(path) else { return false };
+        debug!("skip: {} (blacklisted by '{rule}')", path.display());
+        true
+    }
+
+    /// The --blacklist entry, or ignore file, that excludes `path`.
+    fn blacklist_rule(&self, path: &Path) -> Option<String> {
+        if let Some(name) = path.file_name().and_then(|n| n.to_str())
+            && let Some(entry) = self.blacklist_names.get(name)
+        {
+            return Some(entry.display().to_string());
+        }
+        for blk in &self.blacklist {
+            if path.starts_with(blk) {
+                return Some(blk.display().to_string());
             }
 
             let rel_try = self.local_root.join(blk);
             if path.starts_with(&rel_try) {
-                return true;
+                return Some(blk.display().to_string());
             }
         }
-        false
+        if let Some(gi) = &self.gitignore
+            && gi.is_ignored(path)
+        {
+            return Some(".gitignore".to_string());
+        }
+        if let Some(di) = &self.dockerignore
+            && di.is_ignored(path)
+        {
+            return Some(".dockerignore".to_string());
+        }
+        None
+    }
+}
+
+/// What a walk over the local tree does with each file.
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum Pass {
+    /// Upload everything
+    Full,
+    /// Upload files whose remote size/mtime differ
+    Changed,
+    /// Like `Changed`, but also fix modes on files that are otherwise identical
+    Permissions,
+}
+
+/// Progress of a breadth-first walk over the local tree.
+struct Walk {
+    pass: Pass,
+    queue: VecDeque<PathBuf>,
+    done: HashSet<PathBuf>,
+    sent: Sent,
+    /// Directories walked but not created on the remote yet, relative to the
+    /// local root. Without --preserve-empty-dirs they are only created once
+    /// something is uploaded below them.
+    pending_dirs: HashMap<PathBuf, fs::Metadata>,
+    /// With --preserve-hardlinks, the first path uploaded for each local
+    /// `(dev, ino)` with more than one link.
+    inodes: HashMap<(u64, u64), PathBuf>,
+}
+
+impl Walk {
+    fn new(root: PathBuf, pass: Pass) -> Self {
+        Self {
+            pass,
+            queue: VecDeque::from([root]),
+            done: HashSet::new(),
+            sent: Sent::new(),
+            pending_dirs: HashMap::new(),
+            inodes: HashMap::new(),
+        }
+    }
+}
+
+/// What to do when a remote file changed since rmote last uploaded it and
+/// the local copy changed too.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Conflict {
+    /// Overwrite the remote copy
+    LocalWins,
+    /// Keep the remote copy
+    RemoteWins,
+    /// Keep whichever copy has the later mtime
+    NewerWins,
+    /// Keep the remote copy and warn
+    Skip,
+    /// Rename the remote copy to `<name>.conflict-<unix time>`, then upload
+    Backup,
+}
+
+/// Apply --conflict-command, or else the --conflict policy, to a
+/// conflicting `remote`. Returns whether to upload.
+fn resolve_conflict(cli: &Cli, t: &Target, remote: &Path, local_mtime: i64, remote_mtime: i64) -> Result<bool> {
+    let shown = t.display(remote);
+    if let Some(cmd) = &cli.conflict_command {
+        return run_conflict_command(cmd, t, remote, local_mtime, remote_mtime);
+    }
+    match cli.conflict {
+        Conflict::LocalWins => Ok(true),
+        Conflict::RemoteWins => {
+            info!("conflict: {shown} changed on the remote; keeping it");
+            Ok(false)
+        }
+        Conflict::NewerWins if remote_mtime > local_mtime => {
+            info!("conflict: {shown} is newer on the remote; keeping it");
+            Ok(false)
+        }
+        Conflict::NewerWins => Ok(true),
+        Conflict::Skip => {
+            warn!("conflict: {shown} changed both locally and on the remote; skipped");
+            Ok(false)
+        }
+        Conflict::Backup => {
+            let ts = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let mut name = remote.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".conflict-{ts}"));
+            let backup = remote.with_file_name(name);
+            t.rename(remote, &backup)?;
+            info!("conflict: moved remote {shown} to {}", t.display(&backup));
+            Ok(true)
+        }
     }
 }
 
-fn file_event_receiver(w_rx: Receiver<notify::Result<Event>>, m_tx: Sender<Event>) -> Result<()> {
+/// Ask `cmd` (run by `sh`, with the remote path and the local and remote
+/// mtimes appended) about a conflict: exit 0 uploads, 1 skips, anything
+/// else stops rmote.
+fn run_conflict_command(cmd: &str, t: &Target, remote: &Path, local_mtime: i64, remote_mtime: i64) -> Result<bool> {
+    let shown = t.display(remote);
+    let status = Process::new("sh")
+        .arg("-c")
+        .arg(format!("{cmd} \"$@\""))
+        .arg("rmote")
+        .arg(remote)
+        .arg(local_mtime.to_string())
+        .arg(remote_mtime.to_string())
+        .env("RMOTE_HOST", &t.endpoint.host)
+        .status()
+        .with_context(|| format!("Running --conflict-command for {shown}"))?;
+    match status.code() {
+        Some(0) => {
+            info!("conflict: {shown} changed on the remote; overwriting (--conflict-command)");
+            Ok(true)
+        }
+        Some(1) => {
+            warn!("conflict: {shown} changed both locally and on the remote; skipped (--conflict-command)");
+            Ok(false)
+        }
+        _ => bail!("conflict: --conflict-command {status} for {shown}; stopping"),
+    }
+}
+
+/// Event kinds selectable with --on-kinds.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WatchKind {
+    /// A file or directory appeared
+    Create,
+    /// File contents changed (also modifies the backend can't classify)
+    ModifyData,
+    /// Only permissions, ownership or timestamps changed
+    ModifyMetadata,
+    /// A file or directory was renamed or moved
+    Rename,
+    /// A file or directory was removed
+    Remove,
+}
+
+impl WatchKind {
+    fn of(kind: &EventKind) -> Option<Self> {
+        match kind {
+            EventKind::Create(_) => Some(Self::Create),
+            EventKind::Modify(ModifyKind::Metadata(_)) => Some(Self::ModifyMetadata),
+            EventKind::Modify(ModifyKind::Name(_)) => Some(Self::Rename),
+            EventKind::Modify(_) => Some(Self::ModifyData),
+            EventKind::Remove(_) => Some(Self::Remove),
+            _ => None,
+        }
+    }
+}
+
+fn file_event_receiver(w_rx: Receiver<notify::Result<Event>>, m_tx: Sender<Event>, kinds: &[WatchKind], dump: bool) -> Result<()> {
     for res in w_rx {
         match res {
             Ok(event) => {
+                if dump {
+                    let paths: Vec<_> = event.paths.iter().map(|p| p.display().to_string()).collect();
+                    info!("event: {:?} {}", event.kind, paths.join(" "));
+                }
                 // Only forward interesting kinds
-                match event.kind {
-                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
-                        let _ = m_tx.send(event);
-                    }
-                    _ => {}
+                if WatchKind::of(&event.kind).is_some_and(|k| kinds.contains(&k)) {
+                    let _ = m_tx.send(event);
                 }
             }
-            Err(e) => eprintln!("watch error: {e:?}"),
+            Err(e) => warn!("watch error: {e:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// `clean-temp`: remove the --atomic temp files left on every target by
+/// interrupted runs.
+fn clean_temp(cli: &Cli, min_age: Duration) -> Result<()> {
+    let endpoints = cli.targets()?;
+    let mut total = 0;
+    for endpoint in &endpoints {
+        let mut t = Target::connect(cli, endpoint)?;
+        if endpoints.len() > 1 {
+            t.label = Some(endpoint.host.clone());
+        }
+        let n = t.clean_temp(min_age, cli.dry_run)?;
+        info!("{}: {n} temp files {}", t.display(&t.root), if cli.dry_run { "found" } else { "removed" });
+        total += n;
+    }
+    if endpoints.len() > 1 {
+        info!("{total} temp files {} in total", if cli.dry_run { "found" } else { "removed" });
+    }
+    Ok(())
+}
+
+/// A change failed on one target; the others still get it. The first
+/// failure is returned once all were tried, later ones are only logged.
+fn note_failure(first: &mut Option<anyhow::Error>, shown: &str, e: anyhow::Error) {
+    match first {
+        Some(_) => error!("{shown}: {e:#}"),
+        None => *first = Some(e.context(format!("Syncing {shown}"))),
+    }
+}
+
+/// Whether --keep protects `remote` on `t` from deletes.
+fn kept(keep: &PathGlobs, t: &Target, remote: &Path) -> bool {
+    remote.strip_prefix(&t.root).is_ok_and(|r| keep.matches(r))
+}
+
+/// Before removing a remote directory holding more than --confirm-over files,
+/// say how much would go and get a yes from the terminal. Without one, only
+/// --yes lets it through. Guards against a wrong local root wiping a server.
+fn confirm_delete(cli: &Cli, t: &Target, remote: &Path, owned: &dyn Fn(&Path) -> bool) -> Result<bool> {
+    if cli.yes || !t.is_dir(remote)? {
+        return Ok(true);
+    }
+    let (mut files, mut bytes) = (0, 0);
+    for (p, size) in t.list(remote)? {
+        if owned(&p) {
+            files += 1;
+            bytes += size;
+        }
+    }
+    if files <= cli.confirm_over {
+        return Ok(true);
+    }
+
+    confirm(&format!("Deleting {} would remove {files} files ({})", t.display(remote), human_bytes(bytes)))
+}
+
+/// What deleting `remote` on `t` may remove below it: anything with
+/// --prune-all, otherwise only what rmote put there itself, under the name
+/// it went out with. --keep paths never.
+fn deletable<'k>(t: &Target, keep: &'k PathGlobs, prune_all: bool, remote: &Path) -> impl Fn(&Path) -> bool + use<'k> {
+    let root = t.root.clone();
+    let owned: HashSet<PathBuf> = t.owned_under(remote).into_iter().collect();
+    move |p: &Path| p.strip_prefix(&root).is_ok_and(|r| !keep.matches(r) && (prune_all || owned.contains(p)))
+}
+
+/// Log `summary` and ask on the terminal whether to go ahead.
+fn confirm(summary: &str) -> Result<bool> {
+    warn!("{summary}");
+    if !io::stdin().is_terminal() {
+        warn!("Not confirmed: no terminal to ask on; pass --yes to allow large deletes");
+        return Ok(false);
+    }
+    eprint!("Proceed? [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// --stream: one path per line on stdin, relative to the local root unless
+/// absolute. `-path` deletes; anything else is uploaded, or deleted if gone.
+fn stdin_event_receiver(root: &Path, m_tx: Sender<Event>) -> Result<()> {
+    for line in io::stdin().lock().lines() {
+        let line = line.context("Reading stdin")?;
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, path) = match line.strip_prefix('-') {
+            Some(path) => (EventKind::Remove(RemoveKind::Any), path),
+            None => (EventKind::Modify(ModifyKind::Any), line),
+        };
+        if m_tx.send(Event::new(kind).add_path(root.join(path))).is_err() {
+            break;
         }
     }
     Ok(())
 }
 
-fn expand_tilde(s: &str) -> String {
-    if s.starts_with("~/") {
-        if let Some(home) = env::home_dir() {
-            return home.join(&s[2..]).to_string_lossy().into_owned();
+/// The mode a file with local mode `mode` is given on the remote, before the
+/// remote umask: the permission bits, plus setuid/setgid/sticky only with
+/// --preserve-special-bits.
+fn remote_mode(cli: &Cli, mode: u32) -> i32 {
+    if cli.exec_bit_only {
+        return if mode & 0o100 != 0 { 0o755 } else { 0o644 };
+    }
+    let mask = if cli.preserve_special_bits { 0o7777 } else { 0o777 };
+    (mode & mask) as i32
+}
+
+/// Sort `paths` deepest first, so a removed subtree empties out before its
+/// directory goes instead of relying on the recursive fallback.
+fn deepest_first(paths: &mut [PathBuf]) {
+    paths.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+}
+
+/// Whether following the link `path` inside `dir` would loop back up.
+fn links_to_parent(path: &Path, dir: &Path) -> bool {
+    match (fs::canonicalize(path), fs::canonicalize(dir)) {
+        (Ok(target), Ok(dir)) => dir.starts_with(target),
+        _ => false,
+    }
+}
+
+/// A file counts as binary if a NUL byte shows up in its first few KiB.
+fn looks_binary(path: &Path) -> std::io::Result<bool> {
+    let mut buf = [0u8; 8192];
+    let mut f = File::open(path)?;
+    let mut filled = 0;
+    while filled < buf.len() {
+        match f.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(buf[..filled].contains(&0))
+}
+
+/// Canonicalize everything but the last component, so a symlink keeps its own
+/// name instead of turning into whatever it points at.
+fn absolutize(path: &Path) -> PathBuf {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    };
+    match fs::canonicalize(parent) {
+        Ok(dir) => dir.join(name),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// The file name of every --blacklist entry, which excludes that name
+/// anywhere in the tree. The first entry naming it is the one reported.
+fn blacklist_names(entries: &[PathBuf]) -> HashMap<String, PathBuf> {
+    entries
+        .iter()
+        .rev()
+        .filter_map(|e| e.file_name().map(|n| (n.to_string_lossy().to_string(), e.clone())))
+        .collect()
+}
+
+/// Resolve `.` and `..` without touching the disk, which is only right if
+/// no symlink is involved. Relative paths are taken from the working dir.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = if path.is_relative() { env::current_dir().unwrap_or_default() } else { PathBuf::new() };
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) fn sha256_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut f = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 32 * 1024];
+    loop {
+        match f.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
         }
     }
-    s.to_string()
+    Ok(hasher.finalize().into())
+}
+
+/// Expand `~/path` and `~user/path`. Left unchanged if the home directory
+/// can't be found.
+pub(crate) fn expand_tilde(s: &str) -> String {
+    let Some(after) = s.strip_prefix('~') else {
+        return s.to_string();
+    };
+    let (user, rest) = match after.split_once('/') {
+        Some((user, rest)) => (user, Some(rest)),
+        None => (after, None),
+    };
+    let home = if user.is_empty() { env::home_dir() } else { user_home(user) };
+    match (home, rest) {
+        // A bare `~` or `~user` is the home dir itself, without a trailing `/`
+        (Some(home), None) => home.to_string_lossy().into_owned(),
+        (Some(home), Some(rest)) => home.join(rest).to_string_lossy().into_owned(),
+        (None, _) => s.to_string(),
+    }
+}
+
+/// Home directory of `user` from the passwd database (including NSS sources).
+fn user_home(user: &str) -> Option<PathBuf> {
+    let name = std::ffi::CString::new(user).ok()?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the call and `buf` outlives the use of
+    // `pwd.pw_dir`, which points into it.
+    let rc = unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if rc != 0 || result.is_null() || pwd.pw_dir.is_null() {
+        return None;
+    }
+    let dir = unsafe { std::ffi::CStr::from_ptr(pwd.pw_dir) };
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(dir.to_bytes())))
 }
 
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = Cli::load()?;
+    logging::init(cli.log_format, cli.log_stdout);
+
+    match &cli.command {
+        Some(Command::ExportManifest { output, hash }) => return manifest::export(&cli, output.as_deref(), *hash),
+        Some(Command::Bench) => return bench::run(&cli),
+        Some(Command::CleanTemp { min_age }) => return clean_temp(&cli, *min_age),
+        Some(Command::Verify) => {
+            let drift = App::new(&cli)?.verify()?;
+            if drift > 0 {
+                bail!("{drift} paths differ between the local tree and the remote");
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if cli.preserve_special_bits {
+        warn!("WARNING: --preserve-special-bits is set; setuid/setgid files will be recreated as such on the remote");
+    }
+
+    // Taken before connecting, so a second instance never touches the remote
+    let _pid = cli.pid_file.as_deref().map(PidFile::acquire).transpose()?;
 
     let app = App::new(&cli)?;
 
     app.run(&cli)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(["rmote", "sftp://u@h/srv", "/tmp"].iter().chain(args))
+    }
+
+    #[test]
+    fn special_bits_dropped_by_default() {
+        let cli = cli(&[]);
+        assert_eq!(remote_mode(&cli, 0o1777), 0o777);
+        assert_eq!(remote_mode(&cli, 0o2775), 0o775);
+        assert_eq!(remote_mode(&cli, 0o4755), 0o755);
+        assert_eq!(remote_mode(&cli, 0o100644), 0o644);
+    }
+
+    #[test]
+    fn special_bits_kept_with_preserve() {
+        let cli = cli(&["--preserve-special-bits"]);
+        assert_eq!(remote_mode(&cli, 0o1777), 0o1777);
+        assert_eq!(remote_mode(&cli, 0o2775), 0o2775);
+        assert_eq!(remote_mode(&cli, 0o4755), 0o4755);
+        assert_eq!(remote_mode(&cli, 0o100644), 0o644);
+    }
+
+    #[test]
+    fn exec_bit_only_drops_special_bits() {
+        let cli = cli(&["--exec-bit-only"]);
+        assert_eq!(remote_mode(&cli, 0o4750), 0o755);
+        assert_eq!(remote_mode(&cli, 0o2640), 0o644);
+    }
+
+    #[test]
+    fn expand_tilde_own_home() {
+        let home = env::home_dir().unwrap();
+        assert_eq!(expand_tilde("~"), home.to_string_lossy());
+        assert_eq!(expand_tilde("~/x"), home.join("x").to_string_lossy());
+        assert_eq!(expand_tilde("~/x/"), home.join("x/").to_string_lossy());
+    }
+
+    #[test]
+    fn expand_tilde_other_user() {
+        let home = user_home("root").unwrap();
+        assert_eq!(expand_tilde("~root"), home.to_string_lossy());
+        assert!(!expand_tilde("~root").ends_with('/') || home == Path::new("/"));
+        assert_eq!(expand_tilde("~root/x"), home.join("x").to_string_lossy());
+    }
+
+    #[test]
+    fn expand_tilde_leaves_the_rest() {
+        assert_eq!(expand_tilde("~no-such-user-here/x"), "~no-such-user-here/x");
+        assert_eq!(expand_tilde("/srv/~x"), "/srv/~x");
+        assert_eq!(expand_tilde("x"), "x");
+    }
+
+    #[test]
+    fn deletes_run_deepest_first() {
+        let mut paths: Vec<PathBuf> = ["/w/a", "/w/a/b/c.txt", "/w/d.txt", "/w/a/b"].iter().map(PathBuf::from).collect();
+        deepest_first(&mut paths);
+        for (i, p) in paths.iter().enumerate() {
+            assert!(
+                !paths[..i].iter().any(|earlier| p.starts_with(earlier)),
+                "{} sorted after its parent: {paths:?}",
+                p.display()
+            );
+        }
+        assert_eq!(paths[0], Path::new("/w/a/b/c.txt"));
+        assert_eq!(paths[1], Path::new("/w/a/b"));
+    }
+
+    #[test]
+    fn deepest_first_keeps_siblings_in_order() {
+        let mut paths: Vec<PathBuf> = ["/w/x/1", "/w/y", "/w/x/2", "/w/x"].iter().map(PathBuf::from).collect();
+        deepest_first(&mut paths);
+        assert_eq!(paths, ["/w/x/1", "/w/x/2", "/w/y", "/w/x"].map(PathBuf::from));
+    }
+
+    fn s3_target(args: &[&str]) -> Target {
+        let cli = Cli::parse_from(["rmote", "--backend", "s3", "--s3-bucket", "b", "--remote-dir", "site"].iter().chain(args));
+        Target::connect(&cli, &cli.targets().unwrap()[0]).unwrap()
+    }
+
+    #[test]
+    fn deleting_a_dir_takes_renamed_files_along() {
+        let mut t = s3_target(&["--rename", r"(.*)\.dev\.(.*)=$1.$2"]);
+        let keep = PathGlobs::new(&[]).unwrap();
+        let conf = t.remote_path(Path::new("conf"));
+        let renamed = t.remote_path(Path::new("conf/app.dev.json"));
+        assert_eq!(renamed, conf.join("app.json"));
+        assert!(!t.owns_under(&conf));
+
+        t.own(&renamed);
+        assert!(t.owns_under(&conf));
+        let owned = deletable(&t, &keep, false, &conf);
+        assert!(owned(&conf.join("app.json")));
+        assert!(!owned(&conf.join("app.dev.json")));
+        assert!(!owned(&conf.join("upload.log")));
+
+        t.disown(&conf);
+        assert!(!t.owns_under(&conf));
+    }
+
+    #[test]
+    fn deletes_follow_remote_case() {
+        let mut t = s3_target(&["--remote-case", "lower"]);
+        let keep = PathGlobs::new(&[]).unwrap();
+        t.own(&t.remote_path(Path::new("Docs/README.md")));
+        let docs = t.remote_path(Path::new("Docs"));
+        assert!(t.owns_under(&docs));
+        assert!(deletable(&t, &keep, false, &docs)(&t.root.join("docs/readme.md")));
+    }
+
+    #[test]
+    fn keep_wins_over_prune_all() {
+        let t = s3_target(&[]);
+        let keep = PathGlobs::new(&["uploads".to_string()]).unwrap();
+        let owned = deletable(&t, &keep, true, &t.root);
+        assert!(owned(&t.root.join("app.js")));
+        assert!(!owned(&t.root.join("uploads/avatar.png")));
+    }
+
+    /// The paths `on_deleted` was called with, in order.
+    #[derive(Clone, Default)]
+    struct Deleted(Arc<std::sync::Mutex<Vec<PathBuf>>>);
+
+    impl TransferObserver for Deleted {
+        fn on_deleted(&mut self, rel: &Path) {
+            self.0.lock().unwrap().push(rel.to_path_buf());
+        }
+    }
+
+    #[test]
+    fn burst_of_deletes_runs_deepest_first() {
+        let root = env::temp_dir().join(format!("rmote-burst-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let cli = cli(&["--prune-all"]);
+        let cli = Cli { dir: Some(root.clone()), ..cli };
+        let mut app = App::with_targets(&cli, root.clone(), Vec::new()).unwrap();
+        let deleted = Deleted::default();
+        app.observers.push(Box::new(deleted.clone()));
+
+        // `rm -rf a` as the watcher may report it: in no useful order, and
+        // with a file written just before the tree went
+        let gone = ["a", "a/b/c.txt", "z.txt", "a/b", "a/d.txt", "a/b/e/f.txt", "a/b/e"];
+        let mut events: VecDeque<Event> = gone
+            .iter()
+            .map(|p| Event::new(EventKind::Remove(RemoveKind::Any)).add_path(root.join(p)))
+            .collect();
+        events.push_front(Event::new(EventKind::Modify(ModifyKind::Any)).add_path(root.join("a/b/c.txt")));
+        app.process_events(&mut events).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let deleted = deleted.0.lock().unwrap().clone();
+        assert_eq!(deleted.len(), gone.len(), "{deleted:?}");
+        for (i, p) in deleted.iter().enumerate() {
+            assert!(
+                !deleted[..i].iter().any(|earlier| p.starts_with(earlier)),
+                "{} deleted after its parent: {deleted:?}",
+                p.display()
+            );
+        }
+    }
+}
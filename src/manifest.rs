@@ -0,0 +1,99 @@
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::cli::Cli;
+use crate::target::Target;
+
+/// One remote entry as recorded in the manifest.
+struct Entry {
+    rel: PathBuf,
+    dir: bool,
+    size: u64,
+    mtime: u64,
+    mode: u32,
+    hash: Option<String>,
+}
+
+/// First line of every manifest. Bump the version when the columns change.
+const HEADER: &str = "# rmote-manifest 1";
+
+/// Walk the remote tree and write one tab-separated line per entry:
+/// `path size mtime mode [sha256]`, sorted by path. Directories end in `/`.
+pub fn export(cli: &Cli, output: Option<&Path>, hash: bool) -> Result<()> {
+    let pairs = cli.targets()?;
+    let [endpoint] = pairs.as_slice() else {
+        bail!("export-manifest works on a single host, got {}", pairs.len());
+    };
+    let target = Target::connect(cli, endpoint)?;
+
+    let mut entries = Vec::new();
+    target.walk(&target.root, &mut |remote, stat| {
+        let rel = target.local_name(remote.strip_prefix(&target.root).unwrap_or(remote));
+        let hash = if hash && stat.is_file() {
+            Some(target.sha256(remote)?.iter().map(|b| format!("{b:02x}")).collect())
+        } else {
+            None
+        };
+        entries.push(Entry {
+            rel,
+            dir: stat.is_dir(),
+            size: stat.size.unwrap_or(0),
+            mtime: stat.mtime.unwrap_or(0),
+            mode: stat.perm.unwrap_or(0) & 0o7777,
+            hash,
+        });
+        Ok(())
+    })?;
+    entries.sort_by(|a, b| a.rel.cmp(&b.rel));
+
+    match output {
+        Some(path) => write_atomic(path, &entries)?,
+        None => write_entries(&mut io::stdout().lock(), &entries)?,
+    }
+
+    info!("Exported {} entries from {}", entries.len(), target.display(&target.root));
+    Ok(())
+}
+
+fn write_entries(out: &mut dyn Write, entries: &[Entry]) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "{HEADER}")?;
+    for e in entries {
+        let slash = if e.dir { "/" } else { "" };
+        write!(out, "{}{slash}\t{}\t{}\t{:o}", e.rel.display(), e.size, e.mtime, e.mode)?;
+        if let Some(h) = &e.hash {
+            write!(out, "\t{h}")?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// Write next to `path` and rename over it, so a crash never leaves a
+/// half-written manifest behind. Gzip-compressed if `path` ends in `.gz`.
+fn write_atomic(path: &Path, entries: &[Entry]) -> Result<()> {
+    let name = path.file_name().with_context(|| format!("{} is not a file name", path.display()))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut file = File::create(&tmp).with_context(|| format!("Creating {}", tmp.display()))?;
+    let res = if path.extension().is_some_and(|e| e == "gz") {
+        let mut gz = GzEncoder::new(&mut file, Compression::default());
+        write_entries(&mut gz, entries).and_then(|()| gz.finish().map(drop))
+    } else {
+        write_entries(&mut file, entries)
+    };
+    if let Err(e) = res.and_then(|()| file.sync_all()) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e).with_context(|| format!("Writing {}", tmp.display()));
+    }
+    fs::rename(&tmp, path).with_context(|| format!("Renaming {} to {}", tmp.display(), path.display()))
+}
@@ -0,0 +1,49 @@
+use std::path::Path;
+use tracing::info;
+
+/// One file being uploaded to one target.
+pub struct Transfer<'a> {
+    pub local: &'a Path,
+    /// Path relative to the local root
+    pub rel: &'a Path,
+    /// Destination as shown in log lines, prefixed with the host when
+    /// mirroring to several targets
+    pub remote: &'a str,
+    pub size: u64,
+}
+
+/// Hooks around every file upload, e.g. for a frontend that renders its own
+/// progress instead of reading the log.
+///
+/// Callbacks run synchronously on the thread doing the transfer, in order
+/// start, progress*, then complete or error, and never concurrently for the
+/// same observer. A file the sync passes over after all gets a lone skipped
+/// call instead, and a path removed from every target a lone deleted call.
+/// Slow callbacks slow the upload down.
+pub trait TransferObserver: Send {
+    fn on_file_start(&mut self, _t: &Transfer) {}
+
+    /// `sent` counts the bytes of this file written so far.
+    fn on_file_progress(&mut self, _t: &Transfer, _sent: u64) {}
+
+    fn on_file_complete(&mut self, _t: &Transfer, _bytes: u64) {}
+
+    fn on_error(&mut self, _t: &Transfer, _err: &anyhow::Error) {}
+
+    /// `t` won't be uploaded: it is already identical on the remote, or it
+    /// was left out, e.g. for a name clash or a conflict.
+    fn on_file_skipped(&mut self, _t: &Transfer) {}
+
+    /// `rel`, relative to the local root, is gone locally and was removed
+    /// from every target, along with whatever rmote had synced below it.
+    fn on_deleted(&mut self, _rel: &Path) {}
+}
+
+/// What the binary uses: one log line per uploaded file.
+pub struct LogObserver;
+
+impl TransferObserver for LogObserver {
+    fn on_file_complete(&mut self, t: &Transfer, _bytes: u64) {
+        info!("sync: {} -> {}", t.local.display(), t.remote);
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// First line of every ownership manifest. Bump the version when the format changes.
+const HEADER: &str = "# rmote-owned 1";
+
+/// The remote paths of one target that rmote uploaded, created or found
+/// identical, relative to its root. Unless --prune-all, deletes only remove
+/// these. Kept in a manifest under the state dir, so a restart, with or
+/// without --no-initial-sync, still knows what it may delete.
+#[derive(Default)]
+pub struct Owned {
+    /// Where the manifest lives; `None` when there is no state dir.
+    file: Option<PathBuf>,
+    paths: BTreeSet<PathBuf>,
+    /// Changed since the last `save`.
+    dirty: bool,
+}
+
+impl Owned {
+    /// The manifest of the target `id`, or an empty one if there is none yet.
+    /// An unreadable manifest only warns: owning less just means deleting less.
+    pub fn load(id: &str) -> Self {
+        Self::open(state_dir().map(|dir| dir.join(manifest_name(id))))
+    }
+
+    fn open(file: Option<PathBuf>) -> Self {
+        let mut owned = Self { file, ..Self::default() };
+        if let Some(file) = &owned.file {
+            match fs::read(file) {
+                Ok(text) => match parse(&text) {
+                    Some(paths) => owned.paths = paths,
+                    None => warn!("Ignoring {}: not an rmote ownership manifest", file.display()),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Ignoring {}: {e}", file.display()),
+            }
+        }
+        owned
+    }
+
+    pub fn insert(&mut self, rel: &Path) {
+        self.dirty |= self.paths.insert(rel.to_path_buf());
+    }
+
+    /// Whether `rel` or anything below it is owned.
+    pub fn any_under(&self, rel: &Path) -> bool {
+        self.under(rel).next().is_some()
+    }
+
+    /// The owned paths that are `rel` or below it.
+    pub fn under<'a>(&'a self, rel: &'a Path) -> impl Iterator<Item = &'a Path> {
+        self.paths
+            .range(rel.to_path_buf()..)
+            .take_while(move |p| p.starts_with(rel))
+            .map(PathBuf::as_path)
+    }
+
+    /// Forget `rel` and everything below it, once deleted.
+    pub fn remove_under(&mut self, rel: &Path) {
+        let gone: Vec<PathBuf> = self.under(rel).map(Path::to_path_buf).collect();
+        for p in gone {
+            self.paths.remove(&p);
+            self.dirty = true;
+        }
+    }
+
+    /// Forget everything, for a remote tree that is replaced as a whole.
+    pub fn clear(&mut self) {
+        self.dirty |= !self.paths.is_empty();
+        self.paths.clear();
+    }
+
+    /// Write the manifest if anything changed, next to its old self and
+    /// renamed over it, so a crash never leaves half of it behind.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(file) = self.file.as_ref().filter(|_| self.dirty) else { return Ok(()) };
+        let dir = file.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+        let tmp = file.with_extension("tmp");
+        let mut out = fs::File::create(&tmp).with_context(|| format!("Creating {}", tmp.display()))?;
+        out.write_all(&render(&self.paths))?;
+        out.sync_all()?;
+        fs::rename(&tmp, file).with_context(|| format!("Renaming {} into place", tmp.display()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// `$XDG_STATE_HOME/rmote/owned`, falling back to `~/.local/state` when the
+/// variable is unset or not absolute, as the XDG spec asks.
+fn state_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::home_dir().map(|h| h.join(".local/state")))?;
+    Some(base.join("rmote/owned"))
+}
+
+/// A file name for the target `id` that any id fits into.
+fn manifest_name(id: &str) -> String {
+    let hash = Sha256::digest(id.as_bytes());
+    hash[..16].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// One path per line, with `\` and newlines escaped so any name fits.
+fn render(paths: &BTreeSet<PathBuf>) -> Vec<u8> {
+    let mut out = format!("{HEADER}\n").into_bytes();
+    for p in paths {
+        for &b in p.as_os_str().as_bytes() {
+            match b {
+                b'\\' => out.extend_from_slice(b"\\\\"),
+                b'\n' => out.extend_from_slice(b"\\n"),
+                b => out.push(b),
+            }
+        }
+        out.push(b'\n');
+    }
+    out
+}
+
+/// The paths in a rendered manifest, or `None` if it isn't one.
+fn parse(text: &[u8]) -> Option<BTreeSet<PathBuf>> {
+    let mut lines = text.split(|&b| b == b'\n');
+    if lines.next()? != HEADER.as_bytes() {
+        return None;
+    }
+    let mut paths = BTreeSet::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let mut name = Vec::with_capacity(line.len());
+        let mut bytes = line.iter();
+        while let Some(&b) = bytes.next() {
+            let b = match b {
+                b'\\' => match bytes.next() {
+                    Some(b'n') => b'\n',
+                    Some(&c) => c,
+                    None => b,
+                },
+                b => b,
+            };
+            name.push(b);
+        }
+        paths.insert(PathBuf::from(OsStr::from_bytes(&name)));
+    }
+    Some(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_odd_names() {
+        let paths: BTreeSet<PathBuf> = ["a/b.txt", "back\\slash", "new\nline", "trailing\\"]
+            .iter()
+            .map(PathBuf::from)
+            .chain([PathBuf::from(OsStr::from_bytes(b"latin1-\xe9"))])
+            .collect();
+        assert_eq!(parse(&render(&paths)), Some(paths));
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        assert_eq!(parse(b"some/file\n"), None);
+        assert_eq!(parse(b""), None);
+    }
+
+    #[test]
+    fn under_stops_at_siblings() {
+        let mut owned = Owned::default();
+        for p in ["a", "a/x", "a/y/z", "a-b", "ab", "b"] {
+            owned.insert(Path::new(p));
+        }
+        let under: Vec<_> = owned.under(Path::new("a")).collect();
+        assert_eq!(under, [Path::new("a"), Path::new("a/x"), Path::new("a/y/z")]);
+        assert!(owned.any_under(Path::new("a/y")));
+        assert!(!owned.any_under(Path::new("c")));
+
+        owned.remove_under(Path::new("a"));
+        let left: Vec<_> = owned.under(Path::new("")).collect();
+        assert_eq!(left, [Path::new("a-b"), Path::new("ab"), Path::new("b")]);
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("rmote-owned-{}", std::process::id()));
+        let file = dir.join("manifest");
+        let mut owned = Owned::open(Some(file.clone()));
+        owned.insert(Path::new("conf/app.json"));
+        owned.save().unwrap();
+
+        let owned = Owned::open(Some(file));
+        let all: Vec<_> = owned.under(Path::new("")).collect();
+        assert_eq!(all, [Path::new("conf/app.json")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
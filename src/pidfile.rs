@@ -0,0 +1,73 @@
+use anyhow::{bail, Context, Result};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::{process, thread};
+use tracing::{info, warn};
+
+/// A --pid-file holding this process's PID under an exclusive lock, so a
+/// second rmote given the same file refuses to start. The lock goes with the
+/// process, so a file left by a crashed instance is simply taken over.
+/// Removed on drop, and on SIGINT/SIGTERM.
+pub struct PidFile {
+    path: PathBuf,
+    _file: File,
+}
+
+impl PidFile {
+    pub fn acquire(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Opening pid file {}", path.display()))?;
+        let mut old = String::new();
+        let _ = file.read_to_string(&mut old);
+        let old = old.trim();
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                bail!("Another rmote (pid {old}) is already running with pid file {}", path.display());
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Locking pid file {}", path.display()));
+            }
+        }
+        if !old.is_empty() {
+            info!("Taking over stale pid file {} (pid {old} is gone)", path.display());
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", process::id())?;
+
+        let pid = Self { path: path.to_path_buf(), _file: file };
+        pid.remove_on_signal()?;
+        Ok(pid)
+    }
+
+    /// SIGINT and SIGTERM would skip the drop, so they remove the file
+    /// themselves before exiting.
+    fn remove_on_signal(&self) -> Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM]).context("Installing SIGINT/SIGTERM handler")?;
+        let path = self.path.clone();
+        thread::spawn(move || {
+            if let Some(sig) = signals.forever().next() {
+                let _ = fs::remove_file(&path);
+                process::exit(128 + sig);
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Removing pid file {}: {e}", self.path.display());
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::observer::{Transfer, TransferObserver};
+
+/// Minimum time between two progress lines.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Files at least this big get their own ETA in the progress line.
+const LARGE_FILE: u64 = 8 * 1024 * 1024;
+
+/// Weight of the newest sample in the rolling throughput average.
+const ALPHA: f64 = 0.3;
+
+/// Byte-level progress of a full sync, with ETAs from a rolling average of
+/// the throughput of completed transfers. Logged with --progress, and written
+/// as a JSON snapshot to --progress-file.
+pub struct Progress {
+    log: bool,
+    snapshot: Option<PathBuf>,
+    total: u64,
+    done: u64,
+    /// Bytes per second, or None until the first transfer completes.
+    rate: Option<f64>,
+    file: Option<FileProgress>,
+    last_report: Instant,
+}
+
+struct FileProgress {
+    path: PathBuf,
+    size: u64,
+    sent: u64,
+    started: Instant,
+}
+
+impl Progress {
+    pub fn new(total: u64, log: bool, snapshot: Option<PathBuf>) -> Self {
+        Self {
+            log,
+            snapshot,
+            total,
+            done: 0,
+            rate: None,
+            file: None,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// The rolling average, or the current file's throughput before any
+    /// transfer has completed.
+    fn rate(&self) -> Option<f64> {
+        self.rate.or_else(|| {
+            let f = self.file.as_ref()?;
+            let secs = f.started.elapsed().as_secs_f64();
+            (f.sent > 0 && secs > 0.0).then(|| f.sent as f64 / secs)
+        })
+    }
+
+    fn report(&mut self) {
+        let rate = self.rate();
+        let pct = (self.done * 100).checked_div(self.total).unwrap_or(100).min(100);
+        if let Some(path) = &self.snapshot
+            && let Err(e) = self.write_snapshot(path, pct, rate)
+        {
+            warn!("{e:#}; no longer writing --progress-file");
+            self.snapshot = None;
+        }
+        if !self.log {
+            return;
+        }
+        let mut line = format!("progress: {pct}% ({} / {})", human_bytes(self.done), human_bytes(self.total));
+        if let Some(rate) = rate {
+            let eta = self.total.saturating_sub(self.done) as f64 / rate;
+            line += &format!(", {}/s, ETA {}", human_bytes(rate as u64), human_duration(eta));
+        }
+        if let Some(f) = self.file.as_ref().filter(|f| f.size >= LARGE_FILE) {
+            line += &format!("; {} {}%", f.path.display(), f.sent * 100 / f.size);
+            if let Some(rate) = rate {
+                let eta = f.size.saturating_sub(f.sent) as f64 / rate;
+                line += &format!(" ETA {}", human_duration(eta));
+            }
+        }
+        info!("{line}");
+    }
+
+    /// Replace `path` with the current state through a rename, so a reader
+    /// polling it never sees half a snapshot.
+    fn write_snapshot(&self, path: &Path, pct: u64, rate: Option<f64>) -> Result<()> {
+        let eta = |left: u64| rate.map(|r| (left as f64 / r).round() as u64);
+        let file = self.file.as_ref().map(|f| {
+            json!({
+                "path": f.path,
+                "size": f.size,
+                "sent": f.sent,
+                "eta_secs": eta(f.size.saturating_sub(f.sent)),
+            })
+        });
+        let snapshot = json!({
+            "percent": pct,
+            "bytes_done": self.done,
+            "bytes_total": self.total,
+            "bytes_per_sec": rate.map(|r| r as u64),
+            "eta_secs": eta(self.total.saturating_sub(self.done)),
+            "current_file": file,
+        });
+
+        let name = path.file_name().with_context(|| format!("{} is not a file name", path.display()))?;
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(".tmp");
+        let tmp = path.with_file_name(tmp_name);
+        File::create(&tmp)
+            .and_then(|mut f| writeln!(f, "{snapshot}"))
+            .with_context(|| format!("Writing {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("Renaming {} to {}", tmp.display(), path.display()))
+    }
+}
+
+impl TransferObserver for Progress {
+    fn on_file_start(&mut self, t: &Transfer) {
+        self.file = Some(FileProgress {
+            path: t.rel.to_path_buf(),
+            size: t.size,
+            sent: 0,
+            started: Instant::now(),
+        });
+    }
+
+    fn on_file_progress(&mut self, _t: &Transfer, sent: u64) {
+        if let Some(f) = &mut self.file {
+            self.done += sent.saturating_sub(f.sent);
+            f.sent = sent;
+        }
+        if self.last_report.elapsed() >= INTERVAL {
+            self.last_report = Instant::now();
+            self.report();
+        }
+    }
+
+    /// Fold the finished file's throughput into the rolling average.
+    /// The last file also reports, so the final state is 100%.
+    fn on_file_complete(&mut self, _t: &Transfer, _bytes: u64) {
+        let Some(f) = self.file.take() else { return };
+        let secs = f.started.elapsed().as_secs_f64();
+        if f.sent > 0 && secs > 0.0 {
+            let sample = f.sent as f64 / secs;
+            self.rate = Some(match self.rate {
+                Some(r) => ALPHA * sample + (1.0 - ALPHA) * r,
+                None => sample,
+            });
+        }
+        if self.done >= self.total {
+            self.report();
+        }
+    }
+
+    fn on_error(&mut self, _t: &Transfer, _err: &anyhow::Error) {
+        self.file = None;
+    }
+
+    /// Counted in the total up front, but never sent.
+    fn on_file_skipped(&mut self, t: &Transfer) {
+        self.total = self.total.saturating_sub(t.size);
+        if self.done >= self.total {
+            self.report();
+        }
+    }
+}
+
+pub(crate) fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut v = n as f64;
+    let mut unit = 0;
+    while v >= 1024.0 && unit < UNITS.len() - 1 {
+        v /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{n} B") } else { format!("{v:.1} {}", UNITS[unit]) }
+}
+
+pub(crate) fn human_duration(secs: f64) -> String {
+    let s = secs.round() as u64;
+    match s {
+        0..60 => format!("{s}s"),
+        60..3600 => format!("{}m{:02}s", s / 60, s % 60),
+        _ => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(size: u64) -> Transfer<'static> {
+        Transfer {
+            local: Path::new("/w/a"),
+            rel: Path::new("a"),
+            remote: "/srv/a",
+            size,
+        }
+    }
+
+    #[test]
+    fn skipped_files_leave_the_total() {
+        let mut p = Progress::new(300, false, None);
+        p.on_file_skipped(&transfer(100));
+        assert_eq!(p.total, 200);
+
+        let t = transfer(200);
+        p.on_file_start(&t);
+        p.on_file_progress(&t, 200);
+        p.on_file_complete(&t, 200);
+        assert_eq!(p.done, p.total);
+    }
+
+    #[test]
+    fn total_never_goes_negative() {
+        let mut p = Progress::new(10, false, None);
+        p.on_file_skipped(&transfer(100));
+        assert_eq!(p.total, 0);
+    }
+}
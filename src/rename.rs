@@ -0,0 +1,154 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tracing::warn;
+
+/// One `--rename FROM=TO` rule: a regex matched against the whole path
+/// relative to the local root, and its replacement (`$1`, `${name}`, …).
+#[derive(Debug, Clone)]
+pub struct RenameRule {
+    from: Regex,
+    to: String,
+}
+
+impl RenameRule {
+    /// Split on the first `=`, so the replacement may contain more.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (from, to) = s.split_once('=').ok_or_else(|| format!("expected FROM=TO, got {s:?}"))?;
+        if to.is_empty() {
+            return Err(format!("empty replacement in {s:?}"));
+        }
+        let from = Regex::new(&format!("^(?:{from})$")).map_err(|e| e.to_string())?;
+        Ok(Self { from, to: to.to_string() })
+    }
+}
+
+/// Where `rel` goes on the remote under the first rule that matches it, or
+/// `None` if none does. Results escaping the remote dir are refused.
+pub fn apply(rules: &[RenameRule], rel: &Path) -> Option<PathBuf> {
+    let s = rel.to_str()?;
+    let rule = rules.iter().find(|r| r.from.is_match(s))?;
+    let renamed = PathBuf::from(rule.from.replace(s, rule.to.as_str()).into_owned());
+    if renamed.as_os_str().is_empty() || !renamed.components().all(|c| matches!(c, Component::Normal(_))) {
+        warn!("rename: {s:?} -> {renamed:?} leaves the remote dir; keeping the name");
+        return None;
+    }
+    Some(renamed)
+}
+
+/// Remote names --rename gave to local files, to catch two of them landing
+/// on the same remote path, which would overwrite each other.
+#[derive(Default)]
+pub struct Claims(HashMap<PathBuf, PathBuf>);
+
+impl Claims {
+    /// Another local file that owns the remote name `rel` is renamed to, or
+    /// `None` after `rel` claimed it. A file already named so locally always
+    /// owns it; otherwise the first one to ask does, for as long as `exists`
+    /// says it is still there.
+    pub fn clash(&mut self, rules: &[RenameRule], rel: &Path, exists: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+        let renamed = apply(rules, rel).filter(|r| r != rel)?;
+        if let Some(owner) = self.taken(rules, rel, exists) {
+            return Some(owner);
+        }
+        self.0.insert(renamed, rel.to_path_buf());
+        None
+    }
+
+    /// What `clash` would say, without claiming anything: for a file that
+    /// went away, whose remote name may still belong to another one.
+    pub fn taken(&self, rules: &[RenameRule], rel: &Path, exists: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+        let renamed = apply(rules, rel).filter(|r| r != rel)?;
+        if exists(&renamed) {
+            return Some(renamed);
+        }
+        self.0.get(&renamed).filter(|owner| *owner != rel && exists(owner)).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(specs: &[&str]) -> Vec<RenameRule> {
+        specs.iter().map(|s| RenameRule::parse(s).unwrap()).collect()
+    }
+
+    fn rename(rules: &[RenameRule], rel: &str) -> Option<PathBuf> {
+        apply(rules, Path::new(rel))
+    }
+
+    #[test]
+    fn capture_groups() {
+        let rules = rules(&[r"(.*)\.dev\.(json)=$1.$2"]);
+        assert_eq!(rename(&rules, "conf/config.dev.json"), Some(PathBuf::from("conf/config.json")));
+        assert_eq!(rename(&rules, "config.json"), None);
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = rules(&[r"a\.txt=b.txt", r"a\.txt=c.txt"]);
+        assert_eq!(rename(&rules, "a.txt"), Some(PathBuf::from("b.txt")));
+    }
+
+    #[test]
+    fn escaping_the_root_is_refused() {
+        let rules = rules(&["(.*)=../$1", "x=/etc/x"]);
+        assert_eq!(rename(&rules, "a"), None);
+        assert_eq!(rename(&rules[1..], "x"), None);
+    }
+
+    #[test]
+    fn same_name_locally_wins() {
+        let rules = rules(&[r"(.*)\.dev\.json=$1.json"]);
+        let mut claims = Claims::default();
+        let exists = |p: &Path| p == Path::new("config.json");
+        assert_eq!(claims.clash(&rules, Path::new("config.dev.json"), exists), Some(PathBuf::from("config.json")));
+    }
+
+    #[test]
+    fn two_renames_to_one_name() {
+        let rules = rules(&[r"(.*)\.(dev|test)\.json=$1.json"]);
+        let mut claims = Claims::default();
+        let local = ["config.dev.json", "config.test.json"];
+        let exists = |p: &Path| local.iter().any(|l| p == Path::new(l));
+        assert_eq!(claims.clash(&rules, Path::new("config.dev.json"), exists), None);
+        assert_eq!(claims.clash(&rules, Path::new("config.dev.json"), exists), None);
+        assert_eq!(
+            claims.clash(&rules, Path::new("config.test.json"), exists),
+            Some(PathBuf::from("config.dev.json"))
+        );
+    }
+
+    #[test]
+    fn claim_of_a_deleted_file_is_taken_over() {
+        let rules = rules(&[r"(.*)\.(dev|test)\.json=$1.json"]);
+        let mut claims = Claims::default();
+        assert_eq!(claims.clash(&rules, Path::new("config.dev.json"), |_| false), None);
+        let exists = |p: &Path| p == Path::new("config.test.json");
+        assert_eq!(claims.clash(&rules, Path::new("config.test.json"), exists), None);
+        let exists = |p: &Path| p == Path::new("config.dev.json") || p == Path::new("config.test.json");
+        assert_eq!(
+            claims.clash(&rules, Path::new("config.dev.json"), exists),
+            Some(PathBuf::from("config.test.json"))
+        );
+    }
+
+    #[test]
+    fn losers_deletes_leave_the_winner_alone() {
+        let rules = rules(&[r"(.*)\.(dev|test)\.json=$1.json"]);
+        let mut claims = Claims::default();
+        let exists = |p: &Path| p == Path::new("config.dev.json");
+        assert_eq!(claims.clash(&rules, Path::new("config.dev.json"), exists), None);
+        // config.test.json lost and is gone now: config.json stays the winner's
+        assert_eq!(
+            claims.taken(&rules, Path::new("config.test.json"), exists),
+            Some(PathBuf::from("config.dev.json"))
+        );
+        // The winner itself going away takes its remote copy along
+        assert_eq!(claims.taken(&rules, Path::new("config.dev.json"), |_| false), None);
+        // Without a claim the name is still taken by a file called so locally
+        let exists = |p: &Path| p == Path::new("config.json");
+        assert_eq!(claims.taken(&rules, Path::new("config.test.json"), exists), Some(PathBuf::from("config.json")));
+    }
+}
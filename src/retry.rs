@@ -0,0 +1,250 @@
+use anyhow::Result;
+use ssh2::ErrorCode;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::cli::Cli;
+use crate::target::{RemoteFull, Target};
+
+/// What went wrong with a remote operation, which decides what `run` does next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The SSH connection is gone: reconnect, then try again
+    Connection,
+    /// Remote disk or quota full: hold back for --reconnect-delay-s in case
+    /// space gets freed, then try again
+    NoSpace,
+    /// A timeout or similar hiccup: try again after a growing pause
+    Transient,
+    /// Permission denied, a missing parent and the like: trying again won't help
+    Permanent,
+}
+
+// libssh2 session error codes
+const ERROR_SOCKET_SEND: i32 = -7;
+const ERROR_TIMEOUT: i32 = -9;
+const ERROR_SOCKET_DISCONNECT: i32 = -13;
+const ERROR_CHANNEL_CLOSED: i32 = -26;
+const ERROR_SOCKET_TIMEOUT: i32 = -30;
+const ERROR_EAGAIN: i32 = -37;
+const ERROR_SOCKET_RECV: i32 = -43;
+// SFTP status codes
+const FX_NO_CONNECTION: i32 = 6;
+const FX_CONNECTION_LOST: i32 = 7;
+const FX_NO_SPACE_ON_FILESYSTEM: i32 = 14;
+const FX_QUOTA_EXCEEDED: i32 = 15;
+
+/// The pause before the first Transient retry, doubled for each further one.
+const BACKOFF: Duration = if cfg!(test) { Duration::ZERO } else { Duration::from_secs(1) };
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Sort `e` by the first cause anywhere in its chain that says what it is.
+/// Anything else is Permanent while the connection is `alive`, and a
+/// Connection error once it isn't.
+pub fn classify(e: &anyhow::Error, alive: impl FnOnce() -> bool) -> ErrorClass {
+    for cause in e.chain() {
+        if cause.is::<RemoteFull>() {
+            return ErrorClass::NoSpace;
+        }
+        if let Some(e) = cause.downcast_ref::<ssh2::Error>() {
+            match e.code() {
+                ErrorCode::Session(ERROR_TIMEOUT | ERROR_SOCKET_TIMEOUT | ERROR_EAGAIN) => return ErrorClass::Transient,
+                ErrorCode::Session(
+                    ERROR_SOCKET_SEND | ERROR_SOCKET_RECV | ERROR_SOCKET_DISCONNECT | ERROR_CHANNEL_CLOSED,
+                )
+                | ErrorCode::SFTP(FX_NO_CONNECTION | FX_CONNECTION_LOST) => return ErrorClass::Connection,
+                ErrorCode::SFTP(FX_NO_SPACE_ON_FILESYSTEM | FX_QUOTA_EXCEEDED) => return ErrorClass::NoSpace,
+                _ => {}
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            match e.kind() {
+                ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted => return ErrorClass::Transient,
+                ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof => return ErrorClass::Connection,
+                ErrorKind::StorageFull | ErrorKind::QuotaExceeded => return ErrorClass::NoSpace,
+                ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => return ErrorClass::Permanent,
+                _ => {}
+            }
+        }
+    }
+    if alive() { ErrorClass::Permanent } else { ErrorClass::Connection }
+}
+
+/// What `run` needs of a target to tell and repair a lost connection.
+pub trait Reconnect {
+    fn alive(&self) -> bool;
+    fn reconnect(&mut self, cli: &Cli) -> Result<()>;
+}
+
+impl Reconnect for Target {
+    fn alive(&self) -> bool {
+        Target::alive(self)
+    }
+
+    fn reconnect(&mut self, cli: &Cli) -> Result<()> {
+        Target::reconnect(self, cli)
+    }
+}
+
+/// Run `op` on `t`, and on failure do what the class of the error calls for,
+/// up to --reconnect-attempts times. `what` names the operation in the log.
+pub fn run<R: Reconnect, T>(cli: &Cli, t: &mut R, what: &str, mut op: impl FnMut(&mut R) -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        let e = match op(t) {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        let class = classify(&e, || t.alive());
+        if class == ErrorClass::Permanent || attempt >= cli.reconnect_attempts {
+            return Err(e);
+        }
+        attempt += 1;
+        let tries = format!("{attempt}/{}", cli.reconnect_attempts);
+        match class {
+            ErrorClass::Connection => {
+                warn!("{what}: connection lost ({e:#}); reconnecting ({tries})");
+                t.reconnect(cli)?;
+            }
+            ErrorClass::NoSpace => {
+                error!("{what}: {e:#}; pausing transfers, retrying in {}s ({tries})", cli.reconnect_delay_s);
+                thread::sleep(Duration::from_secs(cli.reconnect_delay_s));
+            }
+            ErrorClass::Transient => {
+                let pause = (BACKOFF * 2u32.pow(attempt.min(16) - 1)).min(MAX_BACKOFF);
+                warn!("{what}: {e:#}; retrying in {}s ({tries})", pause.as_secs());
+                thread::sleep(pause);
+            }
+            ErrorClass::Permanent => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use clap::Parser;
+    use std::io;
+
+    fn io_err(kind: ErrorKind) -> anyhow::Error {
+        io::Error::from(kind).into()
+    }
+
+    fn ssh_err(code: ErrorCode) -> anyhow::Error {
+        ssh2::Error::new(code, "test").into()
+    }
+
+    #[test]
+    fn classify_sorts_by_cause() {
+        let alive = || true;
+        assert_eq!(classify(&RemoteFull { remote: "/srv/a".into() }.into(), alive), ErrorClass::NoSpace);
+        assert_eq!(classify(&ssh_err(ErrorCode::Session(ERROR_TIMEOUT)), alive), ErrorClass::Transient);
+        assert_eq!(classify(&ssh_err(ErrorCode::Session(ERROR_SOCKET_DISCONNECT)), alive), ErrorClass::Connection);
+        assert_eq!(classify(&ssh_err(ErrorCode::SFTP(FX_CONNECTION_LOST)), alive), ErrorClass::Connection);
+        assert_eq!(classify(&ssh_err(ErrorCode::SFTP(FX_QUOTA_EXCEEDED)), alive), ErrorClass::NoSpace);
+        assert_eq!(classify(&io_err(ErrorKind::TimedOut), alive), ErrorClass::Transient);
+        assert_eq!(classify(&io_err(ErrorKind::BrokenPipe), alive), ErrorClass::Connection);
+        assert_eq!(classify(&io_err(ErrorKind::StorageFull), alive), ErrorClass::NoSpace);
+        assert_eq!(classify(&io_err(ErrorKind::PermissionDenied), alive), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn classify_looks_through_context() {
+        let e = io_err(ErrorKind::ConnectionReset).context("upload /srv/a");
+        assert_eq!(classify(&e, || true), ErrorClass::Connection);
+    }
+
+    #[test]
+    fn classify_unknown_by_liveness() {
+        assert_eq!(classify(&anyhow!("no such file"), || true), ErrorClass::Permanent);
+        assert_eq!(classify(&anyhow!("no such file"), || false), ErrorClass::Connection);
+        // A dead link doesn't turn a permission error into a connection error
+        assert_eq!(classify(&io_err(ErrorKind::PermissionDenied), || false), ErrorClass::Permanent);
+    }
+
+    #[derive(Default)]
+    struct Fake {
+        alive: bool,
+        reconnects: u32,
+        calls: u32,
+    }
+
+    impl Reconnect for Fake {
+        fn alive(&self) -> bool {
+            self.alive
+        }
+
+        fn reconnect(&mut self, _cli: &Cli) -> Result<()> {
+            self.reconnects += 1;
+            self.alive = true;
+            Ok(())
+        }
+    }
+
+    fn cli(attempts: u32) -> Cli {
+        let attempts = attempts.to_string();
+        Cli::parse_from(["rmote", "--host", "h", "--reconnect-attempts", &attempts, "--reconnect-delay-s", "0"])
+    }
+
+    /// Fails with `fail()` the first `failures` times, then returns the call count.
+    fn flaky(failures: u32, fail: fn() -> anyhow::Error) -> impl FnMut(&mut Fake) -> Result<u32> {
+        move |t| {
+            t.calls += 1;
+            if t.calls <= failures { Err(fail()) } else { Ok(t.calls) }
+        }
+    }
+
+    #[test]
+    fn run_retries_transient() {
+        let mut t = Fake { alive: true, ..Fake::default() };
+        let res = run(&cli(3), &mut t, "op", flaky(2, || io_err(ErrorKind::TimedOut)));
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(t.reconnects, 0);
+    }
+
+    #[test]
+    fn run_retries_no_space() {
+        let mut t = Fake { alive: true, ..Fake::default() };
+        let res = run(&cli(3), &mut t, "op", flaky(1, || RemoteFull { remote: "/srv/a".into() }.into()));
+        assert_eq!(res.unwrap(), 2);
+    }
+
+    #[test]
+    fn run_reconnects_on_connection_loss() {
+        let mut t = Fake::default();
+        let res = run(&cli(3), &mut t, "op", flaky(1, || anyhow!("channel gone")));
+        assert_eq!(res.unwrap(), 2);
+        assert_eq!(t.reconnects, 1);
+    }
+
+    #[test]
+    fn run_stops_on_permanent() {
+        let mut t = Fake { alive: true, ..Fake::default() };
+        let res = run(&cli(3), &mut t, "op", flaky(5, || io_err(ErrorKind::PermissionDenied)));
+        assert!(res.is_err());
+        assert_eq!(t.calls, 1);
+    }
+
+    #[test]
+    fn run_gives_up_after_attempts() {
+        let mut t = Fake { alive: true, ..Fake::default() };
+        let res = run(&cli(2), &mut t, "op", flaky(10, || io_err(ErrorKind::TimedOut)));
+        assert!(res.is_err());
+        assert_eq!(t.calls, 3);
+    }
+
+    #[test]
+    fn run_without_attempts_fails_at_once() {
+        let mut t = Fake::default();
+        let res = run(&cli(0), &mut t, "op", flaky(1, || anyhow!("channel gone")));
+        assert!(res.is_err());
+        assert_eq!(t.reconnects, 0);
+    }
+}
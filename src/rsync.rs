@@ -0,0 +1,173 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+use crate::cli::{Cli, Endpoint};
+use crate::expand_tilde;
+use crate::filter;
+use crate::target::{RemoteCase, TEMP_MARKER};
+
+/// Whether the local `rsync` can be run at all.
+pub fn available() -> bool {
+    Command::new("rsync").arg("--version").output().is_ok_and(|o| o.status.success())
+}
+
+/// Options rsync can't reproduce, so --use-rsync falls back to SFTP when
+/// any of them is set.
+pub fn unsupported(cli: &Cli) -> Option<&'static str> {
+    [
+        (!cli.rename.is_empty(), "--rename"),
+        (!cli.transform.is_empty(), "--transform"),
+        (cli.remote_encoding.is_some(), "--remote-encoding"),
+        (cli.remote_case != RemoteCase::Preserve, "--remote-case"),
+        (cli.socks5.is_some(), "--socks5"),
+        (cli.use_dockerignore, "--use-dockerignore"),
+        (!cli.ext.is_empty(), "--ext"),
+        (cli.host_key_fingerprint.is_some(), "--host-key-fingerprint"),
+        (cli.staging, "--staging"),
+        (cli.text_only, "--text-only"),
+        (cli.binary_only, "--binary-only"),
+        (cli.skip_empty, "--skip-empty"),
+        (cli.exec_bit_only, "--exec-bit-only"),
+        (cli.remote_umask, "--remote-umask"),
+        (cli.atomic, "--atomic"),
+        (cli.preserve_acls, "--preserve-acls"),
+        (cli.preserve_hardlinks, "--preserve-hardlinks"),
+        // BatchMode ssh has no way to be handed it
+        (cli.passphrase.is_some(), "--passphrase"),
+    ]
+    .into_iter()
+    .find_map(|(set, name)| set.then_some(name))
+}
+
+/// `rsync -az` of `local_root` into the remote dir of `endpoint`, logging in
+/// over `ssh` with the same user, port, key, --bind-address and
+/// --connect-retries as the SFTP connection. --blacklist entries are
+/// excluded, and with --gitignore so is what git ignores. Special bits are dropped and links leaving the tree skipped
+/// unless allowed, as over SFTP. With `delete`, remote files missing locally
+/// go too, except --keep paths and --atomic temp files.
+pub fn command(cli: &Cli, endpoint: &Endpoint, local_root: &Path, delete: bool) -> Command {
+    let mut ssh = format!(
+        "ssh -p {} -i '{}' -o BatchMode=yes",
+        endpoint.port,
+        expand_tilde(&cli.identity).replace('\'', "'\\''")
+    );
+    if let Some(ip) = cli.bind_address {
+        ssh += &format!(" -o BindAddress={ip}");
+    }
+    // ssh retries once a second rather than backing off, but as often
+    if cli.connect_retries > 0 {
+        ssh += &format!(" -o ConnectionAttempts={}", cli.connect_retries + 1);
+    }
+    let mut cmd = Command::new("rsync");
+    cmd.args(["-az", "-e", &ssh]);
+    if delete {
+        cmd.arg("--delete");
+    }
+    if cli.follow_symlinks {
+        cmd.arg("--copy-links");
+    }
+    cmd.arg(if cli.allow_external_symlinks { "--copy-unsafe-links" } else { "--safe-links" });
+    if !cli.preserve_special_bits {
+        cmd.arg("--chmod=ug-s,o-t");
+    }
+    if !cli.preserve_empty_dirs {
+        cmd.arg("--prune-empty-dirs");
+    }
+    // The blacklist matches the last component of an entry anywhere
+    for name in cli.blacklist.iter().filter_map(|e| Path::new(e).file_name()) {
+        let mut exclude = OsString::from("--exclude=");
+        exclude.push(name);
+        cmd.arg(exclude);
+    }
+    if cli.gitignore {
+        cmd.arg("--filter=:- .gitignore");
+        for file in filter::git_exclude_files(local_root) {
+            let mut exclude = OsString::from("--exclude-from=");
+            exclude.push(file);
+            cmd.arg(exclude);
+        }
+    }
+    for keep in &cli.keep {
+        cmd.arg(format!("--filter=P /{keep}"));
+    }
+    cmd.arg(format!("--filter=P *{TEMP_MARKER}*"));
+
+    let mut src = local_root.as_os_str().to_os_string();
+    src.push("/");
+    let host = if endpoint.host.contains(':') { format!("[{}]", endpoint.host) } else { endpoint.host.clone() };
+    let mut dest = OsString::from(format!("{}@{host}:{}", endpoint.user, endpoint.dir));
+    dest.push("/");
+    cmd.arg(src).arg(dest);
+    cmd
+}
+
+/// How many remote paths `cmd` (with `--delete`) would remove, from a
+/// `--dry-run` of it.
+pub fn count_deletes(mut cmd: Command) -> Result<usize> {
+    let out = cmd.args(["--dry-run", "--itemize-changes"]).output().context("Running rsync")?;
+    if !out.status.success() {
+        bail!("rsync --dry-run {}: {}", out.status, String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(out.stdout.split(|&b| b == b'\n').filter(|l| l.starts_with(b"*deleting")).count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::fs;
+
+    fn args(cli: &[&str], local_root: &Path) -> Vec<String> {
+        let cli = Cli::parse_from(["rmote", "--host", "h"].iter().chain(cli));
+        let endpoint = &cli.targets().unwrap()[0];
+        command(&cli, endpoint, local_root, false)
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn ssh(args: &[String]) -> &str {
+        let i = args.iter().position(|a| a == "-e").unwrap();
+        &args[i + 1]
+    }
+
+    #[test]
+    fn ssh_gets_connection_settings() {
+        let args = args(&["--port", "2222", "--bind-address", "10.0.0.7", "--connect-retries", "3"], Path::new("/w"));
+        let ssh = ssh(&args);
+        assert!(ssh.starts_with("ssh -p 2222 "), "{ssh}");
+        assert!(ssh.contains(" -o BindAddress=10.0.0.7"), "{ssh}");
+        assert!(ssh.contains(" -o ConnectionAttempts=4"), "{ssh}");
+    }
+
+    #[test]
+    fn ssh_defaults() {
+        let args = args(&[], Path::new("/w"));
+        let ssh = ssh(&args);
+        assert!(!ssh.contains("BindAddress") && !ssh.contains("ConnectionAttempts"), "{ssh}");
+        assert!(!args.iter().any(|a| a.starts_with("--exclude-from")));
+    }
+
+    #[test]
+    fn passphrase_falls_back_to_sftp() {
+        let cli = Cli::parse_from(["rmote", "--host", "h", "--passphrase", "x"]);
+        assert_eq!(unsupported(&cli), Some("--passphrase"));
+    }
+
+    #[test]
+    fn gitignore_reads_info_exclude() {
+        let repo = std::env::temp_dir().join(format!("rmote-rsync-{}", std::process::id()));
+        let site = repo.join("site");
+        fs::create_dir_all(repo.join(".git/info")).unwrap();
+        fs::create_dir_all(&site).unwrap();
+        fs::write(repo.join(".git/info/exclude"), "*.log\n").unwrap();
+
+        let args = args(&["--gitignore"], &site);
+        let _ = fs::remove_dir_all(&repo);
+        assert!(args.contains(&"--filter=:- .gitignore".to_string()));
+        let exclude = format!("--exclude-from={}", repo.join(".git/info/exclude").display());
+        assert!(args.contains(&exclude), "{args:?}");
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use crate::backend::RemoteBackend;
+use crate::cli::Cli;
+use crate::target::CompareMode;
+
+/// A bucket in an S3-compatible store (--backend s3), driven through the
+/// `aws` CLI so credentials, region and retries come from its usual config.
+/// Keys are the remote paths without the leading `/`. The local mtime goes
+/// along as `mtime` metadata, so size/mtime comparisons work as over SFTP.
+pub struct S3 {
+    bucket: String,
+    endpoint_url: Option<String>,
+    profile: Option<String>,
+}
+
+/// delete-objects takes at most this many keys per call.
+const DELETE_BATCH: usize = 1000;
+
+/// Whether the local `aws` CLI can be run at all.
+pub fn available() -> bool {
+    Command::new("aws").arg("--version").output().is_ok_and(|o| o.status.success())
+}
+
+/// Options that need an SFTP session, so --backend s3 refuses to start with
+/// any of them set.
+pub fn unsupported(cli: &Cli) -> Option<&'static str> {
+    [
+        (cli.inventory.is_some(), "--inventory"),
+        (cli.atomic, "--atomic"),
+        (!cli.append_mode.is_empty(), "--append-mode"),
+        (cli.split_large.is_some(), "--split-large"),
+        (cli.checksum || cli.compare_mode == CompareMode::Checksum, "checksums"),
+        (cli.fast_scan, "--fast-scan"),
+        (cli.remote_umask, "--remote-umask"),
+        (cli.preserve_acls, "--preserve-acls"),
+        (cli.preserve_hardlinks, "--preserve-hardlinks"),
+        (cli.wait_for_writable.is_some(), "--wait-for-writable"),
+        (cli.require_empty_remote, "--require-empty-remote"),
+        (cli.use_rsync, "--use-rsync"),
+        (cli.tar_bulk, "--tar-bulk"),
+        (cli.staging, "--staging"),
+        (cli.final_digest, "--final-digest"),
+        (cli.after_initial_sync.is_some(), "--after-initial-sync"),
+    ]
+    .into_iter()
+    .find_map(|(set, name)| set.then_some(name))
+}
+
+impl S3 {
+    pub fn new(cli: &Cli, bucket: &str) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            endpoint_url: cli.s3_endpoint_url.clone(),
+            profile: cli.s3_profile.clone(),
+        }
+    }
+
+    /// `s3://bucket/key` of `remote`, as shown in log lines; a name that
+    /// can't be a key shows with replacement characters.
+    pub fn url(&self, remote: &Path) -> String {
+        let names: Vec<_> = names(remote).map(OsStr::to_string_lossy).collect();
+        format!("s3://{}/{}", self.bucket, names.join("/"))
+    }
+
+    /// Remove the object `remote` and every key below it that `owned`
+    /// accepts. Returns how many objects went.
+    pub fn delete_tree(&self, remote: &Path, owned: &dyn Fn(&Path) -> bool) -> Result<usize> {
+        let mut keys: Vec<PathBuf> = self.list(remote)?.into_iter().map(|(p, _)| p).filter(|p| owned(p)).collect();
+        if self.stat(remote)?.is_some() {
+            keys.push(remote.to_path_buf());
+        }
+        for batch in keys.chunks(DELETE_BATCH) {
+            let objects = batch.iter().map(|p| Ok(json!({ "Key": key(p)? }))).collect::<Result<Vec<Value>>>()?;
+            let out = run(self
+                .aws()
+                .args(["s3api", "delete-objects", "--bucket", &self.bucket, "--delete"])
+                .arg(json!({ "Objects": objects, "Quiet": true }).to_string()))?;
+            let res: Value = if out.is_empty() { Value::Null } else { serde_json::from_slice(&out)? };
+            if let Some(err) = res.get("Errors").and_then(|e| e.get(0)) {
+                bail!("delete {}: {}", err["Key"], err["Message"]);
+            }
+        }
+        Ok(keys.len())
+    }
+
+    fn aws(&self) -> Command {
+        let mut cmd = Command::new("aws");
+        if let Some(url) = &self.endpoint_url {
+            cmd.args(["--endpoint-url", url]);
+        }
+        if let Some(profile) = &self.profile {
+            cmd.args(["--profile", profile]);
+        }
+        cmd.args(["--output", "json"]);
+        cmd
+    }
+}
+
+impl RemoteBackend for S3 {
+    fn put(&self, local: &Path, remote: &Path, progress: &mut dyn FnMut(u64)) -> Result<()> {
+        let meta = fs::metadata(local).with_context(|| format!("stat {}", local.display()))?;
+        run(self
+            .aws()
+            .args(["s3", "cp", "--only-show-errors", "--metadata"])
+            .arg(format!("mtime={}", meta.mtime()))
+            .arg(local)
+            .arg(format!("s3://{}/{}", self.bucket, key(remote)?)))
+        .with_context(|| format!("put {}", self.url(remote)))?;
+        progress(meta.len());
+        Ok(())
+    }
+
+    fn delete(&self, remote: &Path) -> Result<()> {
+        run(self.aws().args(["s3api", "delete-object", "--bucket", &self.bucket, "--key", &key(remote)?]))
+            .with_context(|| format!("delete {}", self.url(remote)))?;
+        Ok(())
+    }
+
+    fn mkdir(&self, _remote: &Path, _mode: i32) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// An object without `mtime` metadata (not uploaded by rmote) gets 0,
+    /// so it never counts as up to date.
+    fn stat(&self, remote: &Path) -> Result<Option<(u64, i64)>> {
+        let out = self
+            .aws()
+            .args(["s3api", "head-object", "--bucket", &self.bucket, "--key", &key(remote)?])
+            .output()
+            .context("Running aws")?;
+        if !out.status.success() {
+            let err = String::from_utf8_lossy(&out.stderr);
+            if err.contains("(404)") || err.contains("Not Found") {
+                return Ok(None);
+            }
+            bail!("head {}: {}", self.url(remote), err.trim());
+        }
+        let head: Value = serde_json::from_slice(&out.stdout)?;
+        let size = head["ContentLength"].as_u64().unwrap_or(0);
+        let mtime = head["Metadata"]["mtime"].as_str().and_then(|m| m.parse().ok()).unwrap_or(0);
+        Ok(Some((size, mtime)))
+    }
+
+    fn list(&self, remote: &Path) -> Result<Vec<(PathBuf, u64)>> {
+        let prefix = match key(remote)? {
+            k if k.is_empty() => k,
+            k => k + "/",
+        };
+        // The CLI follows the pagination itself
+        let out = run(self
+            .aws()
+            .args(["s3api", "list-objects-v2", "--bucket", &self.bucket, "--prefix", &prefix])
+            .args(["--query", "Contents[].[Key, Size]"]))
+        .with_context(|| format!("list {}", self.url(remote)))?;
+        let objects: Option<Vec<(String, u64)>> = serde_json::from_slice(&out)?;
+        Ok(objects
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(k, size)| Some((remote.join(k.strip_prefix(&prefix)?), size)))
+            .collect())
+    }
+}
+
+/// The object key for `remote`: its plain components joined with `/`, so
+/// `/srv/app/a.txt` and `./a.txt` become `srv/app/a.txt` and `a.txt`. Keys
+/// are UTF-8, so a name that isn't is refused rather than mangled.
+fn key(remote: &Path) -> Result<String> {
+    let parts = names(remote)
+        .map(|name| {
+            name.to_str().with_context(|| {
+                format!("{} isn't valid UTF-8, which S3 keys have to be", remote.display())
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join("/"))
+}
+
+fn names(remote: &Path) -> impl Iterator<Item = &OsStr> {
+    remote.components().filter_map(|c| match c {
+        Component::Normal(name) => Some(name),
+        _ => None,
+    })
+}
+
+/// Run `cmd` and return its stdout; fails with its stderr unless it exits 0.
+fn run(cmd: &mut Command) -> Result<Vec<u8>> {
+    let out = cmd.output().context("Running aws")?;
+    if !out.status.success() {
+        bail!("aws exited with {}: {}", out.status, String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(out.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn key_drops_root_and_dots() {
+        assert_eq!(key(Path::new("/srv/app/a.txt")).unwrap(), "srv/app/a.txt");
+        assert_eq!(key(Path::new("./a.txt")).unwrap(), "a.txt");
+        assert_eq!(key(Path::new("/srv/café")).unwrap(), "srv/café");
+    }
+
+    #[test]
+    fn key_refuses_non_utf8() {
+        let remote = Path::new(OsStr::from_bytes(b"/srv/caf\xe9"));
+        assert!(key(remote).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+/// Open a tunnel to `host:port` through the SOCKS5 proxy on `proxy` (RFC 1928,
+/// no authentication). The host name is passed on unresolved, so it only has
+/// to resolve on the proxy's side.
+pub fn connect(mut proxy: TcpStream, host: &str, port: u16) -> Result<TcpStream> {
+    let Ok(len) = u8::try_from(host.len()) else {
+        bail!("Host name {host:?} is too long for SOCKS5");
+    };
+
+    // Greeting: version 5, one method offered, "no authentication"
+    proxy.write_all(&[5, 1, 0]).context("Sending SOCKS5 greeting")?;
+    let mut reply = [0u8; 2];
+    proxy.read_exact(&mut reply).context("Reading SOCKS5 greeting reply")?;
+    match reply {
+        [5, 0] => {}
+        [5, 0xff] => bail!("SOCKS5 proxy requires authentication, which rmote doesn't support"),
+        [5, m] => bail!("SOCKS5 proxy picked unsupported method {m}"),
+        [v, _] => bail!("Not a SOCKS5 proxy (version {v} in reply)"),
+    }
+
+    // CONNECT by domain name
+    let mut req = vec![5, 1, 0, 3, len];
+    req.extend_from_slice(host.as_bytes());
+    req.extend_from_slice(&port.to_be_bytes());
+    proxy.write_all(&req).context("Sending SOCKS5 CONNECT")?;
+
+    let mut head = [0u8; 4];
+    proxy.read_exact(&mut head).context("Reading SOCKS5 CONNECT reply")?;
+    if head[1] != 0 {
+        bail!("SOCKS5 proxy could not reach {host}:{port}: {}", reply_error(head[1]));
+    }
+    // Skip the bound address, which is of no use here
+    let addr_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut n = [0u8; 1];
+            proxy.read_exact(&mut n)?;
+            n[0] as usize
+        }
+        t => bail!("SOCKS5 proxy replied with unknown address type {t}"),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    proxy.read_exact(&mut bound).context("Reading SOCKS5 CONNECT reply")?;
+    Ok(proxy)
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::cli::Cli;
+
+/// Tar blocks are this big; every header and every file's data fills whole blocks.
+const BLOCK: usize = 512;
+
+/// The largest size or mtime that fits the 11 octal digits of a header field.
+const MAX_OCTAL: u64 = 0o77777777777;
+
+/// Options a tar stream can't reproduce, so --tar-bulk falls back to SFTP
+/// when any of them is set.
+pub fn unsupported(cli: &Cli) -> Option<&'static str> {
+    [
+        (!cli.transform.is_empty(), "--transform"),
+        (cli.atomic, "--atomic"),
+        (cli.split_large.is_some(), "--split-large"),
+        (cli.preserve_acls, "--preserve-acls"),
+        (cli.preserve_hardlinks, "--preserve-hardlinks"),
+        (cli.staging, "--staging"),
+    ]
+    .into_iter()
+    .find_map(|(set, name)| set.then_some(name))
+}
+
+/// Append the regular file `local` to the archive as `name`, with `mode` and
+/// `mtime`. Exactly `size` bytes go out: a file that shrank meanwhile is
+/// padded with zeros and one that grew is cut off, as the header already
+/// promised `size`; the size/mtime pass after the bulk upload fixes both.
+pub fn append(out: &mut dyn Write, name: &Path, local: &Path, mode: u32, size: u64, mtime: i64) -> Result<()> {
+    entry(out, name.as_os_str().as_bytes(), mode, size, mtime, b'0')?;
+
+    let file = File::open(local).with_context(|| format!("Opening {}", local.display()))?;
+    let sent = io::copy(&mut file.take(size), out)?;
+    io::copy(&mut io::repeat(0).take(size - sent), out)?;
+    pad(out, size)
+}
+
+/// Append a directory entry `name` with `mode` and `mtime`. It has to come
+/// before anything inside it, or `tar -x` creates the directory itself with
+/// the remote umask; GNU tar applies the mode and mtime once the whole
+/// archive is extracted, so files still go into a read-only directory.
+pub fn append_dir(out: &mut dyn Write, name: &Path, mode: u32, mtime: i64) -> Result<()> {
+    let mut name = name.as_os_str().as_bytes().to_vec();
+    name.push(b'/');
+    entry(out, &name, mode, 0, mtime, b'5')
+}
+
+/// The header of an entry, preceded by a GNU long name entry when `name`
+/// doesn't fit the 100 bytes of the header's own field.
+fn entry(out: &mut dyn Write, name: &[u8], mode: u32, size: u64, mtime: i64, kind: u8) -> Result<()> {
+    if name.len() > 100 {
+        // GNU long name: a pseudo-entry whose data is the real name
+        let mut long = name.to_vec();
+        long.push(0);
+        out.write_all(&header(b"././@LongLink", 0, long.len() as u64, 0, b'L'))?;
+        out.write_all(&long)?;
+        pad(out, long.len() as u64)?;
+    }
+    out.write_all(&header(&name[..name.len().min(100)], mode, size, mtime.max(0) as u64, kind))?;
+    Ok(())
+}
+
+/// The end-of-archive marker: two empty blocks.
+pub fn finish(out: &mut dyn Write) -> Result<()> {
+    out.write_all(&[0; 2 * BLOCK])?;
+    Ok(())
+}
+
+/// A GNU-style header block. Owner fields stay 0; the remote `tar` runs
+/// with --no-same-owner.
+fn header(name: &[u8], mode: u32, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut h = [0u8; BLOCK];
+    h[..name.len()].copy_from_slice(name);
+    octal(&mut h[100..108], u64::from(mode & 0o7777));
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    number(&mut h[124..136], size);
+    number(&mut h[136..148], mtime);
+    h[156] = kind;
+    h[257..265].copy_from_slice(b"ustar  \0");
+    // The checksum is computed with its own field read as spaces
+    h[148..156].fill(b' ');
+    let sum: u32 = h.iter().map(|&b| u32::from(b)).sum();
+    h[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+    h
+}
+
+/// `n` as zero-padded octal, NUL-terminated, filling `field`.
+fn octal(field: &mut [u8], n: u64) {
+    let digits = format!("{n:0width$o}\0", width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+/// Like `octal`, but past 8 GiB in the base-256 form GNU tar and bsdtar read.
+fn number(field: &mut [u8], n: u64) {
+    if n <= MAX_OCTAL {
+        return octal(field, n);
+    }
+    field.fill(0);
+    let len = field.len();
+    field[len - 8..].copy_from_slice(&n.to_be_bytes());
+    field[0] = 0x80;
+}
+
+/// Zeros up to the end of the block that `len` bytes of data end in.
+fn pad(out: &mut dyn Write, len: u64) -> Result<()> {
+    let rest = (BLOCK - (len % BLOCK as u64) as usize) % BLOCK;
+    out.write_all(&[0; BLOCK][..rest])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::process::{Command, Stdio};
+
+    fn field(h: &[u8], range: std::ops::Range<usize>) -> u64 {
+        let text = std::str::from_utf8(&h[range]).unwrap().trim_end_matches('\0');
+        u64::from_str_radix(text, 8).unwrap()
+    }
+
+    #[test]
+    fn dir_header() {
+        let mut out = Vec::new();
+        append_dir(&mut out, Path::new("a/b"), 0o750, 1_700_000_000).unwrap();
+        assert_eq!(out.len(), BLOCK);
+        assert_eq!(&out[..5], b"a/b/\0");
+        assert_eq!(field(&out, 100..108), 0o750);
+        assert_eq!(field(&out, 124..136), 0);
+        assert_eq!(field(&out, 136..148), 1_700_000_000);
+        assert_eq!(out[156], b'5');
+    }
+
+    #[test]
+    fn long_dir_name() {
+        let name = "d".repeat(150);
+        let mut out = Vec::new();
+        append_dir(&mut out, Path::new(&name), 0o755, 0).unwrap();
+        assert_eq!(out[156], b'L');
+        assert_eq!(&out[BLOCK..BLOCK + 151], format!("{name}/").as_bytes());
+        assert_eq!(out.len(), 3 * BLOCK);
+        assert_eq!(out[2 * BLOCK + 156], b'5');
+    }
+
+    #[test]
+    fn extracted_dirs_keep_their_mode() {
+        let tmp = std::env::temp_dir().join(format!("rmote-tar-{}", std::process::id()));
+        let (src, dst) = (tmp.join("src"), tmp.join("dst"));
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        fs::write(src.join("f"), "hi").unwrap();
+
+        let mut out = Vec::new();
+        append_dir(&mut out, Path::new("ro"), 0o555, 1_000_000).unwrap();
+        append_dir(&mut out, Path::new("ro/sub"), 0o700, 1_000_000).unwrap();
+        append(&mut out, Path::new("ro/sub/f"), &src.join("f"), 0o644, 2, 1_000_000).unwrap();
+        finish(&mut out).unwrap();
+
+        let mut tar = Command::new("tar")
+            .args(["-x", "-p", "--no-same-owner", "-f", "-", "-C"])
+            .arg(&dst)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        tar.stdin.take().unwrap().write_all(&out).unwrap();
+        assert!(tar.wait().unwrap().success());
+
+        let mode = |p: &str| fs::metadata(dst.join(p)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("ro"), 0o555);
+        assert_eq!(mode("ro/sub"), 0o700);
+        assert_eq!(fs::read(dst.join("ro/sub/f")).unwrap(), b"hi");
+        assert_eq!(fs::metadata(dst.join("ro")).unwrap().mtime(), 1_000_000);
+
+        fs::set_permissions(dst.join("ro"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+}
@@ -0,0 +1,1505 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use encoding_rs::{Encoding, UTF_8};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+use ssh2::{ErrorCode, ExtendedData, FileStat, MethodType, OpenFlags, OpenType, Session, Sftp};
+use std::ffi::{OsStr, OsString};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, info_span, warn};
+
+use crate::backend::{Backend, RemoteBackend};
+use crate::cli::{Cli, Endpoint};
+use crate::hostkey::Fingerprint;
+use crate::owned::Owned;
+use crate::rename::{self, RenameRule};
+use crate::s3::S3;
+use crate::socks;
+use crate::{expand_tilde, sha256_file};
+
+/// How to decide that a remote file is already up to date.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum CompareMode {
+    /// Same size. Cheapest, but misses same-size edits
+    Size,
+    /// Same mtime (seconds). Misses edits that keep the mtime
+    Mtime,
+    /// Same size and mtime; one stat per file
+    #[value(name = "size+mtime")]
+    SizeMtime,
+    /// Same SHA-256; reads the local file and the remote copy back in full
+    Checksum,
+}
+
+/// Case of file names on the remote (--remote-case).
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RemoteCase {
+    Lower,
+    Upper,
+    /// Keep names as they are locally
+    Preserve,
+}
+
+impl RemoteCase {
+    /// `name` in this case. Names that aren't UTF-8 are left alone.
+    pub fn apply(self, name: &OsStr) -> OsString {
+        match (self, name.to_str()) {
+            (RemoteCase::Lower, Some(s)) => s.to_lowercase().into(),
+            (RemoteCase::Upper, Some(s)) => s.to_uppercase().into(),
+            _ => name.to_os_string(),
+        }
+    }
+}
+
+/// The remote filesystem (or the user's quota) filled up during an upload.
+#[derive(Debug)]
+pub struct RemoteFull {
+    pub remote: String,
+}
+
+impl fmt::Display for RemoteFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "remote out of space writing {}", self.remote)
+    }
+}
+
+impl std::error::Error for RemoteFull {}
+
+/// Interval of the keepalives `Target::keepalive` sends.
+pub const KEEPALIVE_SECS: u32 = 30;
+
+/// Part of every --atomic temp name: `<name>.rmote-tmp.<token>.<n>`.
+pub const TEMP_MARKER: &str = ".rmote-tmp.";
+
+/// Names for --atomic uploads to write into before renaming into place. The
+/// token is random per run and the counter never repeats within one, so two
+/// instances writing into the same directory can't pick the same name, not
+/// even with the same PID on different machines.
+pub struct TempNames {
+    token: String,
+    next: u64,
+}
+
+impl Default for TempNames {
+    fn default() -> Self {
+        Self {
+            token: format!("{:016x}", RandomState::new().build_hasher().finish()),
+            next: 0,
+        }
+    }
+}
+
+impl TempNames {
+    pub fn next(&mut self, remote: &Path) -> PathBuf {
+        self.next += 1;
+        let mut name = remote.file_name().unwrap_or_default().to_os_string();
+        name.push(format!("{TEMP_MARKER}{}.{}", self.token, self.next));
+        remote.with_file_name(name)
+    }
+}
+
+/// --split-large: files of `min` bytes and up are sent in `parts` ranges at
+/// once, each over a connection of its own opened with `cli`.
+struct Split {
+    min: u64,
+    parts: usize,
+    cli: Box<Cli>,
+}
+
+/// One remote destination: an SFTP connection plus the directory mirrored into.
+pub struct Target {
+    pub endpoint: Endpoint,
+    pub root: PathBuf,
+    /// Prefix for log lines; only set when mirroring to more than one target.
+    pub label: Option<String>,
+    /// Umask of the remote login shell, when probed with --remote-umask.
+    /// Kept across reconnects.
+    umask: Option<u32>,
+    /// Whether the remote has `setfacl`, when probed with --preserve-acls.
+    pub acls: bool,
+    /// False with --no-create-remote-dir: the root is never (re)created.
+    create_root: bool,
+    /// Set when an upload found the root gone and recreated it.
+    root_recreated: AtomicBool,
+    /// Filename charset of the server, if not UTF-8.
+    encoding: Option<&'static Encoding>,
+    /// --rename rules, applied before the case and the encoding.
+    renames: Vec<RenameRule>,
+    case: RemoteCase,
+    split: Option<Split>,
+    /// When recent reconnects happened, for the --reconnect-storm breaker.
+    reconnects: VecDeque<Instant>,
+    /// Size and mtime of every remote file, from one `find` (--fast-scan).
+    /// Only set while a walk compares against it.
+    scan: Option<HashMap<PathBuf, (u64, u64)>>,
+    /// What rmote put on this target itself; see `own`.
+    owned: Owned,
+    store: Store,
+}
+
+/// What a target keeps its files on (--backend).
+enum Store {
+    Sftp { sess: Session, sftp: Sftp },
+    S3(S3),
+}
+
+impl Target {
+    pub fn connect(cli: &Cli, endpoint: &Endpoint) -> Result<Self> {
+        if cli.backend == Backend::S3 {
+            return Ok(Self::with_store(cli, endpoint, Store::S3(S3::new(cli, &endpoint.host)), None, false));
+        }
+        let sess = connect(cli, endpoint)?;
+        let umask = if cli.remote_umask { probe_umask(&sess, &endpoint.host) } else { None };
+        let acls = cli.preserve_acls && probe_setfacl(&sess, &endpoint.host);
+        Self::with_session(cli, endpoint, sess, umask, acls)
+    }
+
+    /// Another root on the server `self` is logged into, over the same SSH
+    /// session with an SFTP channel of its own.
+    pub fn share(&self, cli: &Cli, endpoint: &Endpoint) -> Result<Self> {
+        Self::with_session(cli, endpoint, self.sess()?.clone(), self.umask, self.acls)
+    }
+
+    fn with_session(cli: &Cli, endpoint: &Endpoint, sess: Session, umask: Option<u32>, acls: bool) -> Result<Self> {
+        let sftp = sess.sftp().context("Opening SFTP subsystem failed")?;
+        Ok(Self::with_store(cli, endpoint, Store::Sftp { sess, sftp }, umask, acls))
+    }
+
+    fn with_store(cli: &Cli, endpoint: &Endpoint, store: Store, umask: Option<u32>, acls: bool) -> Self {
+        let root = normalize_remote(Path::new(&endpoint.dir));
+        let id = match &store {
+            Store::S3(s3) => s3.url(&root),
+            Store::Sftp { .. } => format!("{}@{}:{}:{}", endpoint.user, endpoint.host, endpoint.port, root.display()),
+        };
+        Self {
+            endpoint: endpoint.clone(),
+            root,
+            label: None,
+            umask,
+            acls,
+            create_root: !cli.no_create_remote_dir,
+            root_recreated: AtomicBool::new(false),
+            encoding: cli.remote_encoding.filter(|e| *e != UTF_8),
+            renames: cli.rename.clone(),
+            case: cli.remote_case,
+            split: cli.split_large.map(|min| Split {
+                min,
+                parts: cli.split_parts,
+                cli: Box::new(cli.clone()),
+            }),
+            reconnects: VecDeque::new(),
+            scan: None,
+            owned: Owned::load(&id),
+            store,
+        }
+    }
+
+    /// The SFTP channel, for what only SSH targets can do.
+    pub fn sftp(&self) -> Result<&Sftp> {
+        match &self.store {
+            Store::Sftp { sftp, .. } => Ok(sftp),
+            Store::S3(_) => bail!("not supported with --backend s3"),
+        }
+    }
+
+    fn sess(&self) -> Result<&Session> {
+        match &self.store {
+            Store::Sftp { sess, .. } => Ok(sess),
+            Store::S3(_) => bail!("not supported with --backend s3"),
+        }
+    }
+
+    /// The operations every backend has.
+    fn backend(&self) -> &dyn RemoteBackend {
+        match &self.store {
+            Store::Sftp { sftp, .. } => sftp,
+            Store::S3(s3) => s3,
+        }
+    }
+
+    /// The mode a new entry with local mode `mode` should get on this target.
+    pub fn masked(&self, mode: i32) -> i32 {
+        match self.umask {
+            Some(umask) => mode & !(umask as i32),
+            None => mode,
+        }
+    }
+
+    /// Where a path relative to the local root lands on this target, after
+    /// the --rename rules and --remote-case, normalized. Names are re-encoded
+    /// for servers that use another charset (--remote-encoding).
+    pub fn remote_path(&self, rel: &Path) -> PathBuf {
+        let renamed = rename::apply(&self.renames, rel);
+        let rel = renamed.as_deref().unwrap_or(rel);
+        let path = normalize_remote(&match self.case {
+            RemoteCase::Preserve => self.root.join(rel),
+            case => rel.iter().fold(self.root.clone(), |p, c| p.join(case.apply(c))),
+        });
+        let Some(enc) = self.encoding else { return path };
+        let Some(s) = path.to_str() else { return path };
+        let (bytes, _, unmappable) = enc.encode(s);
+        if unmappable {
+            warn!("{s:?} can't be represented in {}; sending the name as is", enc.name());
+            return path;
+        }
+        PathBuf::from(OsStr::from_bytes(&bytes))
+    }
+
+    /// Inverse of the encoding step in `remote_path`, for names read from the server.
+    pub fn local_name(&self, remote: &Path) -> PathBuf {
+        let Some(enc) = self.encoding else { return remote.to_path_buf() };
+        let (s, _, malformed) = enc.decode(remote.as_os_str().as_bytes());
+        if malformed { remote.to_path_buf() } else { PathBuf::from(s.into_owned()) }
+    }
+
+    /// `remote` as shown in log lines.
+    pub fn display(&self, remote: &Path) -> String {
+        if let Store::S3(s3) = &self.store {
+            return s3.url(remote);
+        }
+        match &self.label {
+            Some(label) => format!("{label}:{}", remote.display()),
+            None => remote.display().to_string(),
+        }
+    }
+
+    /// Record `remote` as uploaded, created or found identical by rmote.
+    /// Unless --prune-all, deletes leave everything else alone.
+    pub fn own(&mut self, remote: &Path) {
+        if let Ok(rel) = remote.strip_prefix(&self.root) {
+            self.owned.insert(rel);
+        }
+    }
+
+    /// Whether `remote` or anything below it was put there by rmote.
+    pub fn owns_under(&self, remote: &Path) -> bool {
+        remote.strip_prefix(&self.root).is_ok_and(|rel| self.owned.any_under(rel))
+    }
+
+    /// What rmote put at `remote` or below it, as remote paths.
+    pub fn owned_under(&self, remote: &Path) -> Vec<PathBuf> {
+        let Ok(rel) = remote.strip_prefix(&self.root) else { return Vec::new() };
+        self.owned.under(rel).map(|p| self.root.join(p)).collect()
+    }
+
+    /// Forget `remote` and everything below it, once deleted.
+    pub fn disown(&mut self, remote: &Path) {
+        if let Ok(rel) = remote.strip_prefix(&self.root) {
+            self.owned.remove_under(rel);
+        }
+    }
+
+    /// Forget everything owned, for a tree that is replaced as a whole.
+    pub fn disown_all(&mut self) {
+        self.owned.clear();
+    }
+
+    /// Write down what this target owns, so the next run knows it too.
+    pub fn save_owned(&mut self) -> Result<()> {
+        self.owned.save()
+    }
+
+    /// Whether the root was recreated since the last call, see `ensure_dir`.
+    pub fn take_root_recreated(&self) -> bool {
+        self.root_recreated.swap(false, Ordering::Relaxed)
+    }
+
+    /// Cheap round trip to tell a dropped connection apart from a failed operation.
+    pub fn alive(&self) -> bool {
+        match &self.store {
+            Store::Sftp { sftp, .. } => sftp.realpath(Path::new(".")).is_ok(),
+            // Every call is a fresh request
+            Store::S3(_) => true,
+        }
+    }
+
+    /// Send an SSH keepalive so an idle connection isn't dropped by the
+    /// server or a NAT in between. Nothing to do for an object store.
+    pub fn keepalive(&self) -> Result<()> {
+        let Ok(sess) = self.sess() else { return Ok(()) };
+        // libssh2 only sends once an interval is set
+        sess.set_keepalive(false, KEEPALIVE_SECS);
+        sess.keepalive_send().context("Sending keepalive")?;
+        Ok(())
+    }
+
+    /// Pick up the session of `other`, which reconnected to the same login.
+    pub fn rejoin(&mut self, other: &Target) -> Result<()> {
+        let sess = other.sess()?.clone();
+        let sftp = sess.sftp().context("Opening SFTP subsystem failed")?;
+        self.store = Store::Sftp { sess, sftp };
+        Ok(())
+    }
+
+    pub fn reconnect(&mut self, cli: &Cli) -> Result<()> {
+        if let Store::S3(_) = self.store {
+            return Ok(());
+        }
+        // A link that keeps dropping gets the long delay, so a flapping network
+        // doesn't turn into a stream of logins (and a fail2ban ban)
+        let now = Instant::now();
+        self.reconnects.retain(|t| now.duration_since(*t) < cli.reconnect_window);
+        self.reconnects.push_back(now);
+        let delay = if cli.reconnect_storm > 0 && self.reconnects.len() > cli.reconnect_storm as usize {
+            warn!(
+                "{} reconnects to {} within {}s; backing off to {}s between attempts",
+                self.reconnects.len(),
+                self.endpoint.host,
+                cli.reconnect_window.as_secs(),
+                cli.reconnect_backoff.as_secs()
+            );
+            cli.reconnect_backoff
+        } else {
+            Duration::from_secs(cli.reconnect_delay_s)
+        };
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            thread::sleep(delay);
+            info!("Reconnecting to {} (attempt {attempt}/{}) …", self.endpoint.host, cli.reconnect_attempts);
+
+            let res = connect(cli, &self.endpoint).and_then(|sess| {
+                let sftp = sess.sftp().context("Opening SFTP subsystem failed")?;
+                Ok((sess, sftp))
+            });
+            match res {
+                Ok((sess, sftp)) => {
+                    self.store = Store::Sftp { sess, sftp };
+                    info!("Reconnected.");
+                    return Ok(());
+                }
+                Err(e) if attempt >= cli.reconnect_attempts => {
+                    return Err(e.context(format!("Giving up after {attempt} reconnect attempts")));
+                }
+                Err(e) => warn!("Reconnect failed: {e:#}"),
+            }
+        }
+    }
+
+    /// Upload `local` to `remote`. With `tmp`, the data goes there first and
+    /// is renamed over `remote` once complete. `progress` is told about every
+    /// chunk sent.
+    pub fn copy_file(
+        &mut self,
+        local: &Path,
+        remote: &Path,
+        tmp: Option<&Path>,
+        mode: i32,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<()> {
+        let span = info_span!("transfer", path = %local.display(), bytes = tracing::field::Empty);
+        let _enter = span.enter();
+
+        let res = self.send(local, tmp.unwrap_or(remote), progress);
+        let res = match (res, tmp) {
+            (Ok(meta), Some(tmp)) => self.replace(tmp, remote).map(|()| meta),
+            (res, _) => res,
+        };
+        if res.is_err()
+            && let Some(tmp) = tmp
+        {
+            let _ = self.backend().delete(tmp);
+        }
+        let meta = res?;
+        self.finish(remote, mode, &meta);
+        Ok(())
+    }
+
+    /// Send only what `local` holds beyond the size of the remote copy, for
+    /// files that only grow. A missing remote copy, or one larger than `local`
+    /// (rotated or truncated since), gets a full upload instead.
+    pub fn append_file(&mut self, local: &Path, remote: &Path, mode: i32, progress: &mut dyn FnMut(u64)) -> Result<()> {
+        let mut lf = File::open(local)?;
+        let meta = lf.metadata()?;
+        let Some(start) = self
+            .sftp()?
+            .stat(remote)
+            .ok()
+            .filter(|s| s.is_file())
+            .and_then(|s| s.size)
+            .filter(|&n| n <= meta.len())
+        else {
+            return self.copy_file(local, remote, None, mode, progress);
+        };
+
+        let span = info_span!("transfer", path = %local.display(), bytes = tracing::field::Empty);
+        let _enter = span.enter();
+        let mut rf = self
+            .sftp()?
+            .open_mode(remote, OpenFlags::WRITE | OpenFlags::APPEND, 0o600, OpenType::File)
+            .with_context(|| format!("open {}", self.display(remote)))?;
+        rf.seek(SeekFrom::Start(start))?;
+        lf.seek(SeekFrom::Start(start))?;
+
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut bytes = 0;
+        loop {
+            match lf.read(&mut buf)? {
+                0 => break,
+                n => {
+                    if let Err(e) = rf.write_all(&buf[..n]) {
+                        // What made it so far stays; it's a prefix of the file
+                        if out_of_space(&e, &mut rf) {
+                            return Err(RemoteFull { remote: self.display(remote) }.into());
+                        }
+                        return Err(e.into());
+                    }
+                    bytes += n as u64;
+                    progress(n as u64);
+                }
+            }
+        }
+        span.record("bytes", bytes);
+        self.finish(remote, mode, &meta);
+        Ok(())
+    }
+
+    /// Set mode, and carry the mtime over so later size/mtime checks can skip it.
+    /// An object store got the mtime with the upload and has no modes.
+    fn finish(&self, remote: &Path, mode: i32, meta: &Metadata) {
+        let Ok(sftp) = self.sftp() else { return };
+        let stat = ssh2::FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(self.masked(mode) as u32),
+            atime: Some(meta.atime() as u64),
+            mtime: Some(meta.mtime() as u64),
+        };
+        let _ = sftp.setstat(remote, stat);
+    }
+
+    /// Write the contents of `local` to `remote`, returning the local metadata.
+    fn send(&mut self, local: &Path, remote: &Path, progress: &mut dyn FnMut(u64)) -> Result<Metadata> {
+        if let Some(split) = &self.split
+            && split.parts > 1
+            && let Ok(meta) = fs::metadata(local)
+            && meta.len() >= split.min
+        {
+            self.send_split(split, local, remote, meta.len(), progress)?;
+            return Ok(meta);
+        }
+
+        self.backend().put(local, remote, progress)?;
+        let meta = fs::metadata(local)?;
+        tracing::Span::current().record("bytes", meta.len());
+        Ok(meta)
+    }
+
+    /// `send` for --split-large: every part writes its own range of `remote`
+    /// over its own connection, then the whole file is read back and compared
+    /// with the local one. A failed or mismatching upload is removed.
+    fn send_split(&self, split: &Split, local: &Path, remote: &Path, size: u64, progress: &mut dyn FnMut(u64)) -> Result<()> {
+        // Create (or truncate) it once up front; the parts then only write
+        let sftp = self.sftp()?;
+        drop(match sftp.create(remote) {
+            Ok(f) => f,
+            Err(e) => create_over_readonly(sftp, remote).map_err(|_| e)?,
+        });
+
+        let shown = self.display(remote);
+        let chunk = size.div_ceil(split.parts as u64);
+        let (tx, rx) = mpsc::channel();
+        let res = thread::scope(|s| {
+            let parts: Vec<_> = (0..split.parts as u64)
+                .map(|i| i * chunk..((i + 1) * chunk).min(size))
+                .filter(|r| !r.is_empty())
+                .map(|range| {
+                    let (tx, cli, endpoint, shown) = (tx.clone(), &*split.cli, &self.endpoint, &shown);
+                    s.spawn(move || send_range(cli, endpoint, local, remote, shown, range, &tx))
+                })
+                .collect();
+            drop(tx);
+            for n in rx {
+                progress(n);
+            }
+            parts
+                .into_iter()
+                .try_for_each(|p| p.join().expect("upload thread panicked"))
+        });
+
+        let res = res.and_then(|()| {
+            if sha256_file(local)? != self.sha256(remote)? {
+                bail!("{shown} differs from {} after a split upload", local.display());
+            }
+            Ok(())
+        });
+        if res.is_err() {
+            let _ = sftp.unlink(remote);
+        }
+        tracing::Span::current().record("bytes", size);
+        res
+    }
+
+    /// Rename `from` over `to`. Plain SFTP rename refuses to overwrite, so an
+    /// existing `to` is unlinked and the rename retried.
+    fn replace(&self, from: &Path, to: &Path) -> Result<()> {
+        let sftp = self.sftp()?;
+        if sftp.rename(from, to, None).is_ok() {
+            return Ok(());
+        }
+        let _ = sftp.unlink(to);
+        self.rename(from, to)
+    }
+
+    /// Create and remove a probe file in the root until that works, e.g. while
+    /// a network mount is still coming up. Gives up after `timeout`.
+    pub fn wait_writable(&self, timeout: Duration) -> Result<()> {
+        let probe = self.root.join(format!(".rmote-probe.{}", std::process::id()));
+        let deadline = Instant::now() + timeout;
+        let sftp = self.sftp()?;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let res = sftp.create(&probe).and_then(|f| {
+                drop(f);
+                sftp.unlink(&probe)
+            });
+            match res {
+                Ok(()) => {
+                    info!("remote: {} is writable (attempt {attempt})", self.display(&self.root));
+                    return Ok(());
+                }
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(e).with_context(|| {
+                        format!("{} still not writable after {}s", self.display(&self.root), timeout.as_secs())
+                    });
+                }
+                Err(e) => {
+                    info!("remote: {} not writable yet ({e}); probing again (attempt {attempt})", self.display(&self.root));
+                    thread::sleep(WRITABLE_PROBE_INTERVAL.min(deadline - Instant::now()));
+                }
+            }
+        }
+    }
+
+    /// Remove --atomic temp files below the root that haven't been written to
+    /// for `min_age`, left behind by runs that died mid-upload. Younger ones
+    /// may belong to another instance still running. Returns how many went
+    /// (or would have, with `dry_run`).
+    pub fn clean_temp(&self, min_age: Duration, dry_run: bool) -> Result<usize> {
+        let cutoff = SystemTime::now()
+            .checked_sub(min_age)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let mut stale = Vec::new();
+        self.walk(&self.root, &mut |remote, stat| {
+            if stat.is_file()
+                && is_temp_name(remote)
+                && stat.mtime.is_some_and(|m| m < cutoff)
+            {
+                stale.push(remote.to_path_buf());
+            }
+            Ok(())
+        })?;
+        for remote in &stale {
+            if dry_run {
+                info!("dry-run: would remove temp file {}", self.display(remote));
+                continue;
+            }
+            info!("remote: removing stale temp file {}", self.display(remote));
+            self.sftp()?
+                .unlink(remote)
+                .with_context(|| format!("unlink {}", self.display(remote)))?;
+        }
+        Ok(stale.len())
+    }
+
+    /// Delete `remote`. Inside a directory, only files `owned` accepts are
+    /// removed; a directory that still holds anything else stays.
+    pub fn delete(&mut self, remote: &Path, owned: &dyn Fn(&Path) -> bool) -> Result<()> {
+        if let Store::S3(s3) = &self.store {
+            let n = s3.delete_tree(remote, owned)?;
+            info!("remote: deleted {n} objects at {}", self.display(remote));
+            return Ok(());
+        }
+        // Try file unlink first, then rmdir. If directory not empty, attempt recursive.
+        if self.backend().delete(remote).is_ok() {
+            info!("remote: deleted file {}", self.display(remote));
+            return Ok(());
+        }
+
+        // If it's a directory, try to remove recursively
+        if self.is_dir(remote)? {
+            self.remove_dir_recursive(remote, owned)?;
+            if self.exists(remote)? {
+                info!("remote: kept dir {} (holds files rmote didn't sync; see --prune-all)", self.display(remote));
+            } else {
+                info!("remote: removed dir {}", self.display(remote));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn ensure_dir(&self, remote_dir: Option<&Path>, mode: i32) -> Result<()> {
+        for built in dir_chain(remote_dir.unwrap_or(&self.root)) {
+            let is_root = remote_dir.is_some() && built == self.root;
+            if is_root && !self.create_root {
+                continue;
+            }
+
+            match self.backend().mkdir(&built, self.masked(mode)) {
+                // Someone removed the whole remote tree while we were watching
+                Ok(true) if is_root => {
+                    warn!("Remote directory {} had vanished; recreated it", self.display(&built));
+                    self.root_recreated.store(true, Ordering::Relaxed);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // If it already exists (race), ignore
+                    if !self.exists(&built)? {
+                        return Err(e).with_context(|| format!("mkdir {:?}", built));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.sftp()?
+            .rename(from, to, None)
+            .with_context(|| format!("rename {} -> {}", self.display(from), self.display(to)))
+    }
+
+    /// `<root>.<suffix>`, next to the root.
+    pub fn sibling(&self, suffix: &str) -> Result<PathBuf> {
+        let Some(name) = self.root.file_name() else {
+            bail!("{} has no parent to stage next to", self.display(&self.root));
+        };
+        let mut name = name.to_os_string();
+        name.push(format!(".{suffix}"));
+        Ok(self.root.with_file_name(name))
+    }
+
+    /// Put the fully uploaded `staging` dir in place of the root. The old
+    /// tree is renamed to `<root>.old` first and removed once the swap is
+    /// done; if `staging` can't be moved, it is renamed back.
+    pub fn swap_in(&mut self, staging: &Path) -> Result<()> {
+        let old = self.sibling("old")?;
+        if self.exists(&old)? {
+            self.remove_dir_recursive(&old, &|_| true)?;
+        }
+        let had_root = self.exists(&self.root)?;
+        if had_root {
+            self.rename(&self.root, &old)?;
+        }
+        if let Err(e) = self.rename(staging, &self.root) {
+            if had_root {
+                let _ = self.rename(&old, &self.root);
+            }
+            return Err(e);
+        }
+        self.remove_dir_recursive(&old, &|_| true)
+    }
+
+    /// Permission bits of a remote entry, or None if it doesn't exist (or
+    /// the backend has no modes).
+    pub fn mode(&self, remote: &Path) -> Result<Option<i32>> {
+        let Ok(sftp) = self.sftp() else { return Ok(None) };
+        match sftp.stat(remote) {
+            Ok(stat) => Ok(stat.perm.map(|p| (p & 0o7777) as i32)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn set_mode(&self, remote: &Path, mode: i32) -> Result<()> {
+        let stat = ssh2::FileStat {size: None, uid: None, atime: None, gid: None, mtime: None, perm: Some(mode as u32)};
+        self.sftp()?
+            .setstat(remote, stat)
+            .with_context(|| format!("chmod {}", self.display(remote)))
+    }
+
+    /// Whether the remote file already matches `local` (with metadata `meta`)
+    /// under `mode`. This is the one place that decides what counts as unchanged.
+    pub fn matches(&self, remote: &Path, local: &Path, meta: &Metadata, mode: CompareMode) -> bool {
+        let (size, mtime) = match &self.scan {
+            Some(scan) => match scan.get(remote) {
+                Some(&(size, mtime)) => (Some(size), Some(mtime)),
+                None => return false,
+            },
+            None => {
+                let Ok(Some((size, mtime))) = self.backend().stat(remote) else { return false };
+                (Some(size), Some(mtime as u64))
+            }
+        };
+        let size = size == Some(meta.size());
+        let mtime = mtime == Some(meta.mtime() as u64);
+        match mode {
+            CompareMode::Size => size,
+            CompareMode::Mtime => mtime,
+            CompareMode::SizeMtime => size && mtime,
+            // Differing sizes settle it without reading anything
+            CompareMode::Checksum => {
+                size && matches!((sha256_file(local), self.sha256(remote)), (Ok(a), Ok(b)) if a == b)
+            }
+        }
+    }
+
+    /// List every regular file below the root with a single remote `find`, so
+    /// `matches` compares against memory instead of a stat per file. Needs a
+    /// `find` with `-printf` (GNU); if that fails, warn and keep stat'ing.
+    pub fn fast_scan(&mut self) {
+        match self.remote_find() {
+            Ok(files) => {
+                info!("fast scan: {} remote files under {}", files.len(), self.display(&self.root));
+                self.scan = Some(files);
+            }
+            // A first sync into a dir that isn't there yet: nothing to compare
+            Err(_) if matches!(self.exists(&self.root), Ok(false)) => self.scan = Some(HashMap::new()),
+            Err(e) => warn!("--fast-scan failed on {}: {e:#}; stat'ing each file instead", self.endpoint.host),
+        }
+    }
+
+    /// Drop the --fast-scan listing once the walk that used it is over.
+    pub fn end_scan(&mut self) {
+        self.scan = None;
+    }
+
+    fn remote_find(&self) -> Result<HashMap<PathBuf, (u64, u64)>> {
+        let mut ch = self.sess()?.channel_session()?;
+        // NUL-terminated, as names may hold spaces and newlines
+        ch.exec(&format!("find {} -type f -printf '%s %T@ %P\\0'", shell_quote(&self.root)))?;
+        let mut out = Vec::new();
+        ch.read_to_end(&mut out)?;
+        ch.wait_close()?;
+        let status = ch.exit_status()?;
+        if status != 0 {
+            bail!("find exited with status {status}");
+        }
+        out.split(|&b| b == 0)
+            .filter(|r| !r.is_empty())
+            .map(|r| parse_find_record(r).map(|(rel, state)| (self.root.join(rel), state)))
+            .collect()
+    }
+
+    /// Give `remote` the ACL `acl::read` returned for its local copy, with
+    /// the remote `setfacl`.
+    pub fn set_acl(&self, remote: &Path, acl: &str) -> Result<()> {
+        self.exec(&format!("setfacl --set-file=- -- {}", shell_quote(remote)), acl.as_bytes())
+            .with_context(|| format!("setfacl {}", self.display(remote)))
+    }
+
+    /// Make `remote` another name for the remote file `existing`, with `ln`
+    /// over SSH since SFTP v3 has no hard links.
+    pub fn hardlink(&self, existing: &Path, remote: &Path) -> Result<()> {
+        self.exec(&format!("ln -f -- {} {}", shell_quote(existing), shell_quote(remote)), &[])
+            .with_context(|| format!("ln {} {}", self.display(existing), self.display(remote)))
+    }
+
+    /// Whether `name` is on the PATH of the remote login shell.
+    pub fn has_command(&self, name: &str) -> bool {
+        self.exec(&format!("command -v {name}"), &[]).is_ok()
+    }
+
+    /// Run `cmd` in the remote shell with `input` on its stdin; fails with
+    /// its stderr unless it exits 0.
+    fn exec(&self, cmd: &str, input: &[u8]) -> Result<()> {
+        let mut ch = self.sess()?.channel_session()?;
+        ch.exec(cmd)?;
+        ch.write_all(input)?;
+        ch.send_eof()?;
+        let mut err = String::new();
+        ch.stderr().read_to_string(&mut err)?;
+        ch.wait_close()?;
+        match ch.exit_status()? {
+            0 => Ok(()),
+            status => bail!("exited with {status}: {}", err.trim()),
+        }
+    }
+
+    /// Run `cmd` in the remote shell from the root, handing every line it
+    /// prints (stdout and stderr) to `line` as it comes. Returns its exit status.
+    pub fn run_in_root(&self, cmd: &str, line: &mut dyn FnMut(&str)) -> Result<i32> {
+        let mut ch = self.sess()?.channel_session()?;
+        ch.handle_extended_data(ExtendedData::Merge)?;
+        ch.exec(&format!("cd {} && {cmd}", shell_quote(&self.root)))?;
+        ch.send_eof()?;
+        for l in BufReader::new(&mut ch).lines() {
+            line(&l?);
+        }
+        ch.wait_close()?;
+        Ok(ch.exit_status()?)
+    }
+
+    /// Stream what `write` produces into a remote `tar -x` below the root,
+    /// keeping the modes and mtimes of the archive. Fails with tar's stderr
+    /// unless it exits 0.
+    pub fn untar(&self, write: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+        let mut ch = self.sess()?.channel_session()?;
+        ch.exec(&format!("tar -x -p --no-same-owner -f - -C {}", shell_quote(&self.root)))?;
+        {
+            let mut out = BufWriter::with_capacity(64 * 1024, &mut ch);
+            write(&mut out)?;
+            out.flush()?;
+        }
+        ch.send_eof()?;
+        let mut err = String::new();
+        ch.stderr().read_to_string(&mut err)?;
+        ch.wait_close()?;
+        match ch.exit_status()? {
+            0 => Ok(()),
+            status => bail!("tar exited with {status}: {}", err.trim()),
+        }
+    }
+
+    /// Make `remote` a symlink to `target`, replacing a file or link already
+    /// there. An object store has no links, so there it's skipped.
+    pub fn symlink(&self, remote: &Path, target: &Path) -> Result<()> {
+        let Ok(sftp) = self.sftp() else {
+            warn!("skip: {} (no symlinks with --backend s3; see --follow-symlinks)", self.display(remote));
+            return Ok(());
+        };
+        if self.read_link(remote).as_deref() == Some(target) {
+            return Ok(());
+        }
+        let _ = sftp.unlink(remote);
+        // OpenSSH swaps the SFTP symlink arguments; ssh2 follows OpenSSH.
+        sftp
+            .symlink(target, remote)
+            .with_context(|| format!("symlink {} -> {}", self.display(remote), target.display()))?;
+        info!("remote: linked {} -> {}", self.display(remote), target.display());
+        Ok(())
+    }
+
+    pub fn read_link(&self, remote: &Path) -> Option<PathBuf> {
+        self.sftp().ok()?.readlink(remote).ok()
+    }
+
+    /// Size and mtime of a remote regular file.
+    pub fn file_state(&self, remote: &Path) -> Option<(u64, i64)> {
+        self.backend().stat(remote).ok().flatten()
+    }
+
+    /// In an object store, a "directory" exists while keys below it do.
+    pub fn exists(&self, remote: &Path) -> Result<bool> {
+        let sftp = match &self.store {
+            Store::Sftp { sftp, .. } => sftp,
+            Store::S3(s3) => return Ok(s3.stat(remote)?.is_some() || !s3.list(remote)?.is_empty()),
+        };
+        match sftp.stat(remote) {
+            Ok(_) => Ok(true),
+            Err(_) => Ok(false),
+        }
+    }
+
+    pub fn is_dir(&self, remote: &Path) -> Result<bool> {
+        let sftp = match &self.store {
+            Store::Sftp { sftp, .. } => sftp,
+            Store::S3(s3) => return Ok(!s3.list(remote)?.is_empty()),
+        };
+        match sftp.stat(remote) {
+            Ok(stat) => Ok(stat.is_dir()),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Every file below `remote`, at any depth, with its size.
+    pub fn list(&self, remote: &Path) -> Result<Vec<(PathBuf, u64)>> {
+        self.backend().list(remote).with_context(|| format!("listing {}", self.display(remote)))
+    }
+
+    pub fn remove_dir_recursive(&mut self, remote: &Path, owned: &dyn Fn(&Path) -> bool) -> Result<()> {
+        // Best effort: whatever can't be listed or removed is left behind, and
+        // the final rmdir simply fails.
+        let sftp = self.sftp()?;
+        let _ = self.walk(remote, &mut |child, stat| {
+            if stat.is_dir() {
+                let _ = sftp.rmdir(child);
+            } else if owned(child) {
+                let _ = sftp.unlink(child);
+            }
+            Ok(())
+        });
+        let _ = sftp.rmdir(remote);
+        Ok(())
+    }
+
+    /// Depth-first walk below `remote` via `readdir`. Each entry is visited
+    /// after everything inside it, so a directory is already empty by the time
+    /// a removal pass gets to it.
+    pub fn walk(&self, remote: &Path, f: &mut dyn FnMut(&Path, &FileStat) -> Result<()>) -> Result<()> {
+        let entries = self
+            .sftp()?
+            .readdir(remote)
+            .with_context(|| format!("readdir {}", self.display(remote)))?;
+
+        for (child, stat) in entries {
+            if let Some(name) = child.file_name()
+                && (name == "." || name == "..")
+            {
+                continue;
+            }
+            if stat.is_dir() {
+                self.walk(&child, f)?;
+            }
+            f(&child, &stat)?;
+        }
+        Ok(())
+    }
+
+    /// SHA-256 of a remote file, read back over SFTP.
+    pub fn sha256(&self, remote: &Path) -> Result<[u8; 32]> {
+        let mut rf = self
+            .sftp()?
+            .open(remote)
+            .with_context(|| format!("open {}", self.display(remote)))?;
+        let mut hasher = Sha256::new();
+        let mut buf = [0u8; 32 * 1024];
+        loop {
+            match rf.read(&mut buf).with_context(|| format!("read {}", self.display(remote)))? {
+                0 => break,
+                n => hasher.update(&buf[..n]),
+            }
+        }
+        Ok(hasher.finalize().into())
+    }
+}
+
+impl RemoteBackend for Sftp {
+    fn put(&self, local: &Path, remote: &Path, progress: &mut dyn FnMut(u64)) -> Result<()> {
+        let mut rf = match self.create(remote) {
+            Ok(f) => f,
+            Err(e) => create_over_readonly(self, remote).map_err(|_| e)?,
+        };
+        let mut lf = File::open(local)?;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            match lf.read(&mut buf)? {
+                0 => break,
+                n => {
+                    if let Err(e) = rf.write_all(&buf[..n]) {
+                        if out_of_space(&e, &mut rf) {
+                            // Don't leave a truncated copy behind taking up what little is left
+                            drop(rf);
+                            let _ = self.unlink(remote);
+                            return Err(RemoteFull { remote: remote.display().to_string() }.into());
+                        }
+                        return Err(e.into());
+                    }
+                    progress(n as u64);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&self, remote: &Path) -> Result<()> {
+        Ok(self.unlink(remote)?)
+    }
+
+    fn mkdir(&self, remote: &Path, mode: i32) -> Result<bool> {
+        Sftp::mkdir(self, remote, mode)?;
+        Ok(true)
+    }
+
+    fn stat(&self, remote: &Path) -> Result<Option<(u64, i64)>> {
+        match Sftp::stat(self, remote) {
+            Ok(stat) if stat.is_file() => Ok(stat.size.zip(stat.mtime).map(|(size, mtime)| (size, mtime as i64))),
+            Ok(_) => Ok(None),
+            Err(e) if e.code() == ErrorCode::SFTP(FX_NO_SUCH_FILE) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self, remote: &Path) -> Result<Vec<(PathBuf, u64)>> {
+        let mut files = Vec::new();
+        for (child, stat) in self.readdir(remote)? {
+            if child.file_name().is_some_and(|n| n == "." || n == "..") {
+                continue;
+            }
+            if stat.is_dir() {
+                files.extend(RemoteBackend::list(self, &child)?);
+            } else {
+                files.push((child, stat.size.unwrap_or(0)));
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// SFTP status for a path that doesn't exist.
+const FX_NO_SUCH_FILE: i32 = 2;
+
+/// `create` can't truncate a remote file without write permission. Grant it
+/// owner-write (or failing that, unlink it) and try again. The intended
+/// mode is restored by the setstat after the upload.
+fn create_over_readonly(sftp: &Sftp, remote: &Path) -> Result<ssh2::File> {
+    let stat = sftp.stat(remote)?;
+    let perm = stat.perm.unwrap_or(0);
+    if !stat.is_file() || perm & 0o200 != 0 {
+        bail!("not a read-only file");
+    }
+
+    info!("remote: {} is read-only ({:o}), making it writable to overwrite", remote.display(), perm & 0o7777);
+    let writable = ssh2::FileStat {size: None, uid: None, atime: None, gid: None, mtime: None, perm: Some(perm | 0o200)};
+    if sftp.setstat(remote, writable).is_ok()
+        && let Ok(f) = sftp.create(remote)
+    {
+        return Ok(f);
+    }
+
+    info!("remote: unlinking read-only {} to replace it", remote.display());
+    sftp.unlink(remote)?;
+    Ok(sftp.create(remote)?)
+}
+
+/// Whether `remote` is named exactly like an --atomic temp file, marker,
+/// token and counter included, so nothing else is ever taken for one.
+pub fn is_temp_name(remote: &Path) -> bool {
+    let name = remote.file_name().map_or(&[][..], |n| n.as_bytes());
+    let marker = TEMP_MARKER.as_bytes();
+    let Some(at) = name.windows(marker.len()).rposition(|w| w == marker) else {
+        return false;
+    };
+    let suffix = &name[at + marker.len()..];
+    let Some(dot) = suffix.iter().position(|&b| b == b'.') else {
+        return false;
+    };
+    let (token, n) = (&suffix[..dot], &suffix[dot + 1..]);
+    token.len() == 16
+        && token.iter().all(u8::is_ascii_hexdigit)
+        && !n.is_empty()
+        && n.iter().all(u8::is_ascii_digit)
+}
+
+fn connect(cli: &Cli, endpoint: &Endpoint) -> Result<Session> {
+    let _span = info_span!("connect", host = endpoint.host, port = endpoint.port).entered();
+
+    // Only the transport is retried; bad credentials won't get better by waiting.
+    let mut delay = cli.connect_retry_delay;
+    let mut attempt = 0;
+    let sess = loop {
+        match open_session(cli, endpoint) {
+            Ok(sess) => break sess,
+            Err(e) if attempt < cli.connect_retries => {
+                attempt += 1;
+                let wait = jitter(delay);
+                warn!("{e:#}; retrying in {:.1}s ({attempt}/{})", wait.as_secs_f64(), cli.connect_retries);
+                thread::sleep(wait);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    // Not retried either: a different key is no transport hiccup
+    if let Some(pin) = &cli.host_key_fingerprint {
+        pin.check(&sess, &endpoint.host)?;
+    } else if cli.insecure_no_host_check {
+        let key = Fingerprint::of(&sess).map_or_else(|| "unknown key".to_string(), |f| f.to_string());
+        warn!("WARNING: not verifying that {} is who it claims to be (host key {key}); --insecure-no-host-check is set", endpoint.host);
+    }
+
+    let privkey = expand_tilde(&cli.identity);
+    let pubkey = expand_tilde(&cli.identity_pub);
+
+    sess.userauth_pubkey_file(
+        &endpoint.user,
+        Some(Path::new(&pubkey)),
+        Path::new(&privkey),
+        cli.passphrase.as_deref(),
+    )
+    .with_context(|| "SSH public key authentication failed")?;
+
+    if !sess.authenticated() {
+        bail!("Authentication failed");
+    }
+    Ok(sess)
+}
+
+/// One `size mtime path` record of the --fast-scan `find`. The mtime has a
+/// fractional part; SFTP only reports whole seconds.
+fn parse_find_record(rec: &[u8]) -> Result<(&Path, (u64, u64))> {
+    let bad = || anyhow::anyhow!("Unexpected find output {:?}", String::from_utf8_lossy(rec));
+    let mut fields = rec.splitn(3, |&b| b == b' ');
+    let (Some(size), Some(mtime), Some(rel)) = (fields.next(), fields.next(), fields.next()) else {
+        return Err(bad());
+    };
+    let size = std::str::from_utf8(size).ok().and_then(|s| s.parse().ok()).ok_or_else(bad)?;
+    let secs = mtime.split(|&b| b == b'.').next().unwrap_or_default();
+    let mtime = std::str::from_utf8(secs).ok().and_then(|s| s.parse().ok()).ok_or_else(bad)?;
+    Ok((Path::new(OsStr::from_bytes(rel)), (size, mtime)))
+}
+
+/// The directories to create, outermost first, for `dir` to exist: one per
+/// real name in it, so `/srv/app/` gives `/srv` and `/srv/app`. "/", "."
+/// and ".." already exist and are only kept as part of the longer paths.
+fn dir_chain(dir: &Path) -> Vec<PathBuf> {
+    let mut built = PathBuf::new();
+    let mut chain = Vec::new();
+    for comp in dir.components() {
+        built.push(comp.as_os_str());
+        if matches!(comp, Component::Normal(_)) {
+            chain.push(built.clone());
+        }
+    }
+    chain
+}
+
+/// `path` without doubled or trailing slashes and without `.` components,
+/// so `/srv/app/` and `/srv//app/./x` become `/srv/app` and `/srv/app/x`.
+/// Only a leading `.` stays, as the whole of a default `--remote-dir .`;
+/// `..` is left alone, as the remote may resolve it through a symlink.
+fn normalize_remote(path: &Path) -> PathBuf {
+    let clean: PathBuf = path.components().collect();
+    if clean.as_os_str().is_empty() { PathBuf::from(".") } else { clean }
+}
+
+/// `path` in single quotes for the remote shell.
+/// Bytes that aren't UTF-8, as under --remote-encoding, can't go into the
+/// command string as they are, so they are spelled as octal escapes for
+/// `printf` and spliced in; every POSIX shell understands that.
+fn shell_quote(path: &Path) -> String {
+    let mut quoted = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        if !chunk.valid().is_empty() {
+            quoted += &format!("'{}'", chunk.valid().replace('\'', "'\\''"));
+        }
+        if !chunk.invalid().is_empty() {
+            let octal: String = chunk.invalid().iter().map(|b| format!("\\{b:03o}")).collect();
+            quoted += &format!("\"$(printf '{octal}')\"");
+        }
+    }
+    if quoted.is_empty() { "''".to_string() } else { quoted }
+}
+
+/// Ask the login shell for its umask. On failure, warn and leave modes as they are.
+fn probe_umask(sess: &Session, host: &str) -> Option<u32> {
+    match read_umask(sess) {
+        Ok(umask) => {
+            info!("remote umask on {host}: {umask:03o}");
+            Some(umask)
+        }
+        Err(e) => {
+            warn!("Could not probe umask on {host}: {e:#}; keeping local modes");
+            None
+        }
+    }
+}
+
+/// Whether `setfacl` is on the remote PATH. If not, warn: ACLs are skipped there.
+fn probe_setfacl(sess: &Session, host: &str) -> bool {
+    let found = || -> Result<i32> {
+        let mut ch = sess.channel_session()?;
+        ch.exec("command -v setfacl")?;
+        ch.read_to_string(&mut String::new())?;
+        ch.wait_close()?;
+        Ok(ch.exit_status()?)
+    };
+    match found() {
+        Ok(0) => true,
+        Ok(_) => {
+            warn!("No setfacl on {host}; ACLs are not mirrored there");
+            false
+        }
+        Err(e) => {
+            warn!("Could not probe for setfacl on {host}: {e:#}; ACLs are not mirrored there");
+            false
+        }
+    }
+}
+
+fn read_umask(sess: &Session) -> Result<u32> {
+    let mut ch = sess.channel_session()?;
+    ch.exec("umask")?;
+    let mut out = String::new();
+    ch.read_to_string(&mut out)?;
+    ch.wait_close()?;
+    let umask = u32::from_str_radix(out.trim(), 8).with_context(|| format!("Unexpected umask output {out:?}"))?;
+    Ok(umask & 0o777)
+}
+
+/// SFTP v3 servers (OpenSSH) report ENOSPC as a generic failure, so when the
+/// error itself doesn't say, ask the filesystem whether any blocks are left.
+fn out_of_space(e: &std::io::Error, file: &mut ssh2::File) -> bool {
+    let msg = e.to_string();
+    msg.contains("no space on filesystem")
+        || msg.contains("quota exceeded")
+        || file.statvfs().is_ok_and(|vfs| vfs.f_bavail == 0)
+}
+
+/// One --split-large part: bytes `range` of `local`, written at the same
+/// offset of the already created `remote`.
+fn send_range(
+    cli: &Cli,
+    endpoint: &Endpoint,
+    local: &Path,
+    remote: &Path,
+    shown: &str,
+    range: Range<u64>,
+    sent: &Sender<u64>,
+) -> Result<()> {
+    let sess = connect(cli, endpoint)?;
+    let sftp = sess.sftp().context("Opening SFTP subsystem failed")?;
+    let mut rf = sftp
+        .open_mode(remote, OpenFlags::WRITE, 0o600, OpenType::File)
+        .with_context(|| format!("open {shown}"))?;
+    rf.seek(SeekFrom::Start(range.start))?;
+    let mut lf = File::open(local)?;
+    lf.seek(SeekFrom::Start(range.start))?;
+    let mut lf = lf.take(range.end - range.start);
+
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match lf.read(&mut buf)? {
+            0 => return Ok(()),
+            n => {
+                if let Err(e) = rf.write_all(&buf[..n]) {
+                    if out_of_space(&e, &mut rf) {
+                        return Err(RemoteFull { remote: shown.to_string() }.into());
+                    }
+                    return Err(e.into());
+                }
+                let _ = sent.send(n as u64);
+            }
+        }
+    }
+}
+
+const WRITABLE_PROBE_INTERVAL: Duration = Duration::from_secs(2);
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// TCP connect plus SSH handshake, i.e. everything before authentication.
+fn open_session(cli: &Cli, endpoint: &Endpoint) -> Result<Session> {
+    let Endpoint { host, port, .. } = endpoint;
+    let tcp = match &cli.socks5 {
+        Some((proxy, proxy_port)) => {
+            let tcp = tcp_connect(cli, proxy, *proxy_port).context("Connecting to SOCKS5 proxy")?;
+            socks::connect(tcp, host, *port)?
+        }
+        None => tcp_connect(cli, host, *port)?,
+    };
+
+    let mut sess = Session::new().expect("Failed to create SSH session");
+    sess.set_tcp_stream(tcp);
+    sess.handshake().context("SSH handshake failed")?;
+    log_server(&sess);
+    Ok(sess)
+}
+
+/// What the handshake settled on, for diagnosing cipher mismatches.
+fn log_server(sess: &Session) {
+    let method = |m| sess.methods(m).unwrap_or("?");
+    debug!("server version: {}", sess.banner().unwrap_or("?"));
+    debug!(
+        "negotiated kex {}, host key {}, cipher {}/{}, mac {}/{}",
+        method(MethodType::Kex),
+        method(MethodType::HostKey),
+        method(MethodType::CryptCs),
+        method(MethodType::CryptSc),
+        method(MethodType::MacCs),
+        method(MethodType::MacSc),
+    );
+}
+
+fn tcp_connect(cli: &Cli, host: &str, port: u16) -> Result<TcpStream> {
+    match cli.bind_address {
+        Some(local) => connect_from(local, host, port),
+        None => TcpStream::connect((host, port)).with_context(|| format!("Connecting to {host}:{port}")),
+    }
+}
+
+/// Like `TcpStream::connect`, but from a socket bound to `local` first.
+/// Only remote addresses of the same family as `local` are tried.
+fn connect_from(local: IpAddr, host: &str, port: u16) -> Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Resolving {host}"))?
+        .filter(|a| a.is_ipv4() == local.is_ipv4())
+        .collect();
+    if addrs.is_empty() {
+        bail!("{host} has no {} address to reach from {local}", if local.is_ipv4() { "IPv4" } else { "IPv6" });
+    }
+
+    let mut last_err = None;
+    for addr in addrs {
+        let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        sock.bind(&SocketAddr::new(local, 0).into())
+            .with_context(|| format!("Binding to local address {local} failed (is it assigned to an interface?)"))?;
+        match sock.connect(&addr.into()) {
+            Ok(()) => return Ok(sock.into()),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    let e = last_err.expect("at least one address was tried");
+    Err(e).with_context(|| format!("Connecting to {host}:{port} from {local}"))
+}
+
+/// Spread `d` over 50%..150% so a fleet of clients doesn't retry in lockstep.
+fn jitter(d: Duration) -> Duration {
+    let r = RandomState::new().build_hasher().finish();
+    d.mul_f64(0.5 + (r % 1000) as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// A target that never connects anywhere: an S3 one, whose paths are
+    /// built just like an SFTP one's.
+    fn target(args: &[&str]) -> Target {
+        let cli = Cli::parse_from(["rmote", "--backend", "s3", "--s3-bucket", "b"].iter().chain(args));
+        Target::connect(&cli, &cli.targets().unwrap()[0]).unwrap()
+    }
+
+    #[test]
+    fn masked_keeps_special_bits() {
+        let mut t = target(&[]);
+        assert_eq!(t.masked(0o2775), 0o2775);
+        t.umask = Some(0o022);
+        assert_eq!(t.masked(0o2775), 0o2755);
+        assert_eq!(t.masked(0o1777), 0o1755);
+        assert_eq!(t.masked(0o4755), 0o4755);
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let t = target(&["--remote-dir", "/srv", "--remote-encoding", "latin1"]);
+        let remote = t.remote_path(Path::new("café/Übersicht.txt"));
+        assert_eq!(remote.as_os_str().as_bytes(), b"/srv/caf\xe9/\xdcbersicht.txt");
+        assert_eq!(t.local_name(&remote), Path::new("/srv/café/Übersicht.txt"));
+
+        let t = target(&["--remote-dir", "/srv", "--remote-encoding", "shift_jis"]);
+        let remote = t.remote_path(Path::new("資料/メモ.txt"));
+        assert_ne!(remote.as_os_str().as_bytes(), "/srv/資料/メモ.txt".as_bytes());
+        assert_eq!(t.local_name(&remote), Path::new("/srv/資料/メモ.txt"));
+    }
+
+    #[test]
+    fn unmappable_names_go_out_as_is() {
+        let t = target(&["--remote-dir", "/srv", "--remote-encoding", "latin1"]);
+        let remote = t.remote_path(Path::new("日本/é.txt"));
+        assert_eq!(remote, Path::new("/srv/日本/é.txt"));
+    }
+
+    #[test]
+    fn undecodable_names_come_back_as_is() {
+        let t = target(&["--remote-encoding", "shift_jis"]);
+        let raw = PathBuf::from(OsStr::from_bytes(b"/srv/\x82"));
+        assert_eq!(t.local_name(&raw), raw);
+    }
+
+    #[test]
+    fn utf8_names_untouched_without_encoding() {
+        let t = target(&["--remote-dir", "/srv"]);
+        let remote = t.remote_path(Path::new("café.txt"));
+        assert_eq!(remote.as_os_str().as_bytes(), "/srv/café.txt".as_bytes());
+        assert_eq!(t.local_name(&remote), remote);
+    }
+
+    fn chain(dir: &str) -> Vec<PathBuf> {
+        dir_chain(Path::new(dir))
+    }
+
+    #[test]
+    fn dir_chain_of_absolute_root() {
+        assert_eq!(chain("/a/b/c"), ["/a", "/a/b", "/a/b/c"].map(PathBuf::from));
+        assert!(chain("/").is_empty());
+    }
+
+    #[test]
+    fn dir_chain_of_relative_root() {
+        assert_eq!(chain("a/b"), ["a", "a/b"].map(PathBuf::from));
+        assert_eq!(chain("./a/../b"), ["./a", "./a/../b"].map(PathBuf::from));
+        assert!(chain(".").is_empty());
+    }
+
+    #[test]
+    fn dir_chain_ignores_trailing_slash() {
+        assert_eq!(chain("/srv/app/"), chain("/srv/app"));
+        assert_eq!(chain("srv//app/"), ["srv", "srv/app"].map(PathBuf::from));
+    }
+
+    /// What `sh` makes of `shell_quote(path)` as a single argument.
+    fn through_sh(path: &Path) -> Vec<u8> {
+        let out = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote(path)))
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        out.stdout
+    }
+
+    #[test]
+    fn shell_quote_keeps_raw_bytes() {
+        for name in [&b"/srv/caf\xe9/x"[..], b"\x82\xa0", b"it's a \xff$(x) `y`", b"/srv/\xe9'\xe8'", b""] {
+            let path = Path::new(OsStr::from_bytes(name));
+            assert_eq!(through_sh(path), name, "{}", shell_quote(path));
+        }
+    }
+
+    #[test]
+    fn shell_quote_of_encoded_name() {
+        let t = target(&["--remote-dir", "/srv", "--remote-encoding", "latin1"]);
+        let remote = t.remote_path(Path::new("café.txt"));
+        assert_eq!(shell_quote(&remote), "'/srv/caf'\"$(printf '\\351')\"'.txt'");
+        assert_eq!(through_sh(&remote), remote.as_os_str().as_bytes());
+    }
+
+    #[test]
+    fn find_records() {
+        let (rel, state) = parse_find_record(b"1234 1700000000.5200000000 a dir/f i\nle").unwrap();
+        assert_eq!(rel, Path::new("a dir/f i\nle"));
+        assert_eq!(state, (1234, 1_700_000_000));
+        let (rel, _) = parse_find_record(b"1 2 caf\xe9").unwrap();
+        assert_eq!(rel.as_os_str().as_bytes(), b"caf\xe9");
+        assert!(parse_find_record(b"12 x").is_err());
+        assert!(parse_find_record(b"x 1.0 a").is_err());
+    }
+
+    #[test]
+    fn normalize_remote_cleans_paths() {
+        assert_eq!(normalize_remote(Path::new("/srv/app/")), Path::new("/srv/app"));
+        assert_eq!(normalize_remote(Path::new("/srv//app/./x")), Path::new("/srv/app/x"));
+        assert_eq!(normalize_remote(Path::new("./a/./b/")), Path::new("./a/b"));
+        assert_eq!(normalize_remote(Path::new("/srv/../app")), Path::new("/srv/../app"));
+        assert_eq!(normalize_remote(Path::new(".")), Path::new("."));
+        assert_eq!(normalize_remote(Path::new("")), Path::new("."));
+    }
+
+    #[test]
+    fn remote_dir_with_trailing_slash() {
+        let t = target(&["--remote-dir", "/srv/app/"]);
+        assert_eq!(t.root.as_os_str(), "/srv/app");
+        assert_eq!(t.remote_path(Path::new("css/site.css")).as_os_str(), "/srv/app/css/site.css");
+    }
+
+    #[test]
+    fn remote_case_paths_normalized() {
+        for case in ["lower", "upper", "preserve"] {
+            let t = target(&["--remote-dir", "/srv/app/", "--remote-case", case]);
+            let plain = t.remote_path(Path::new("a/b"));
+            assert_eq!(t.remote_path(Path::new("a/./b")), plain, "{case}");
+            assert_eq!(t.remote_path(Path::new("a//b/")), plain, "{case}");
+            assert_eq!(t.remote_path(Path::new("./a/b")), plain, "{case}");
+        }
+        let t = target(&["--remote-dir", "/srv", "--remote-case", "upper"]);
+        assert_eq!(t.remote_path(Path::new("./x/../y")), Path::new("/srv/X/../Y"));
+    }
+
+    #[test]
+    fn default_remote_dir() {
+        let t = target(&[]);
+        assert_eq!(t.root.as_os_str(), ".");
+        assert_eq!(t.remote_path(Path::new("index.html")).as_os_str(), "./index.html");
+        assert_eq!(t.remote_path(Path::new("a/./b")).as_os_str(), "./a/b");
+    }
+}
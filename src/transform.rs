@@ -0,0 +1,91 @@
+use anyhow::{bail, Context, Result};
+use globset::{Glob, GlobMatcher};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::ErrorKind;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// One `--transform GLOB=CMD` rule: files whose path relative to the local
+/// root matches the glob are uploaded as what `CMD` prints for them.
+#[derive(Debug, Clone)]
+pub struct Transform {
+    glob: GlobMatcher,
+    cmd: String,
+}
+
+/// Output of a transform, in a local temp file removed on drop.
+pub struct Output(PathBuf);
+
+impl Output {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+impl Transform {
+    /// Split on the first `=`, so the command may contain more.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (glob, cmd) = s.split_once('=').ok_or_else(|| format!("expected GLOB=CMD, got {s:?}"))?;
+        if cmd.trim().is_empty() {
+            return Err(format!("empty command in {s:?}"));
+        }
+        let glob = Glob::new(glob).map_err(|e| e.to_string())?.compile_matcher();
+        Ok(Self { glob, cmd: cmd.to_string() })
+    }
+
+    /// The shell command, as given after the `=`.
+    pub fn cmd(&self) -> &str {
+        &self.cmd
+    }
+
+    /// Run the command through `sh` with `local` on stdin, into a temp file
+    /// that gets `mtime` so the remote copy ends up with the local one.
+    pub fn apply(&self, local: &Path, mtime: SystemTime) -> Result<Output> {
+        let input = File::open(local).with_context(|| format!("Opening {}", local.display()))?;
+        let (out, file) = temp_file()?;
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&self.cmd)
+            .stdin(input)
+            .stdout(Stdio::from(file.try_clone()?))
+            .status()
+            .with_context(|| format!("Running {:?}", self.cmd))?;
+        if !status.success() {
+            bail!("{:?} {status}", self.cmd);
+        }
+        file.set_modified(mtime)?;
+        Ok(out)
+    }
+}
+
+/// A new file in the temp dir that only this user can read. The name is
+/// random and the file must not exist yet, so on a shared /tmp nobody can
+/// plant a file or symlink there beforehand to be written through.
+fn temp_file() -> Result<(Output, File)> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let token = RandomState::new().build_hasher().finish();
+        let path = std::env::temp_dir().join(format!(".rmote-transform-{}-{token:016x}-{n}", process::id()));
+        match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => return Ok((Output(path), file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("Creating {}", path.display())),
+        }
+    }
+}
+
+/// The first rule matching `rel`, if any.
+pub fn find<'a>(rules: &'a [Transform], rel: &Path) -> Option<&'a Transform> {
+    rules.iter().find(|t| t.glob.is_match(rel))
+}
@@ -0,0 +1,155 @@
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::target::{is_temp_name, Target};
+use crate::{links_to_parent, sha256_file, App, Change};
+
+/// Something a sync would put on the remote.
+enum Local {
+    File { path: PathBuf, rel: PathBuf, meta: Metadata },
+    /// A directory symlink, mirrored as a link unless --follow-symlinks
+    Link { rel: PathBuf, target: PathBuf },
+}
+
+impl App {
+    /// Compare the local tree with every target without changing anything.
+    /// Prints an `M`, `A` or `D` line per drifted path, like a porcelain dry
+    /// run, and returns how many there were.
+    pub(crate) fn verify(&self) -> Result<usize> {
+        let mut local = Vec::new();
+        self.collect(&self.local_root, &mut local)?;
+        let mode = self.cli.compare_mode;
+
+        let mut drift = 0;
+        for t in &self.targets {
+            let mut report = |change: Change, rel: &Path| {
+                drift += 1;
+                match &t.label {
+                    Some(label) => println!("{} {label}:{}", change.code(), rel.display()),
+                    None => println!("{} {}", change.code(), rel.display()),
+                }
+            };
+
+            let mut expected = HashSet::new();
+            for entry in &local {
+                let (rel, same) = match entry {
+                    Local::File { path, rel, meta } => (rel, t.matches(&t.remote_path(rel), path, meta, self.compare_mode(rel, mode))),
+                    Local::Link { rel, target } => (rel, t.read_link(&t.remote_path(rel)).as_ref() == Some(target)),
+                };
+                let remote = t.remote_path(rel);
+                if !same {
+                    report(if t.exists(&remote)? { Change::Modified } else { Change::Added }, rel);
+                }
+                expected.insert(remote);
+            }
+
+            // Remote files nothing local accounts for
+            for (remote, _) in t.list(&t.root)? {
+                if expected.contains(&remote) || is_temp_name(&remote) {
+                    continue;
+                }
+                let rel = t.local_name(remote.strip_prefix(&t.root).unwrap_or(&remote));
+                if !self.is_blacklisted(&self.local_root.join(&rel)) {
+                    report(Change::Deleted, &rel);
+                }
+            }
+        }
+
+        info!("Verified {} local entries against {}: {drift} differ", local.len(), targets(&self.targets));
+        Ok(drift)
+    }
+
+    /// --final-digest: one SHA-256 over the whole tree, computed from the local
+    /// files and again from each target's copies read back, which must agree.
+    /// Prints the digest to stdout for comparing environments.
+    pub(crate) fn final_digest(&self) -> Result<()> {
+        let mut local = Vec::new();
+        self.collect(&self.local_root, &mut local)?;
+        let mut files: Vec<(&Path, &Path)> = local
+            .iter()
+            .filter_map(|e| match e {
+                Local::File { path, rel, .. } => Some((path.as_path(), rel.as_path())),
+                Local::Link { .. } => None,
+            })
+            .collect();
+        files.sort_by_key(|&(_, rel)| rel);
+
+        info!("Computing tree digest over {} files …", files.len());
+        let digest = tree_digest(files.iter().map(|&(path, rel)| Ok((rel, sha256_file(path)?))))?;
+        for t in &self.targets {
+            let remote = tree_digest(files.iter().map(|&(_, rel)| Ok((rel, t.sha256(&t.remote_path(rel))?))))?;
+            if remote != digest {
+                bail!("Tree digest of {} is {}, local is {}", t.display(&t.root), hex(&remote), hex(&digest));
+            }
+        }
+        info!("Tree digest matches on every target: {}", hex(&digest));
+        println!("{}", hex(&digest));
+        Ok(())
+    }
+
+    /// Everything below `dir` a full sync would send, by the same rules as
+    /// `walk_tree`.
+    fn collect(&self, dir: &Path, out: &mut Vec<Local>) -> Result<()> {
+        for entry in fs::read_dir(dir).with_context(|| format!("Reading {}", dir.display()))? {
+            let path = entry?.path();
+            if self.is_blacklisted(&path) {
+                continue;
+            }
+            let rel = path.strip_prefix(&self.local_root)?.to_path_buf();
+
+            let mut meta = fs::symlink_metadata(&path)?;
+            if meta.file_type().is_symlink() {
+                if !self.cli.allow_external_symlinks && self.external_symlink(&path) {
+                    continue;
+                }
+                if !self.cli.follow_symlinks {
+                    if path.is_dir() {
+                        let target = fs::read_link(&path).with_context(|| format!("Reading link {}", path.display()))?;
+                        out.push(Local::Link { rel, target });
+                    }
+                    continue;
+                }
+                if path.is_dir() && links_to_parent(&path, dir) {
+                    continue;
+                }
+                let Ok(target) = fs::metadata(&path) else { continue };
+                meta = target;
+            }
+
+            if meta.is_dir() {
+                self.collect(&path, out)?;
+            } else if meta.is_file() && !self.skip_file(&path, &meta) {
+                out.push(Local::File { path, rel, meta });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn targets(targets: &[Target]) -> String {
+    targets.iter().map(|t| t.display(&t.root)).collect::<Vec<_>>().join(", ")
+}
+
+/// SHA-256 over the leaves in order, each leaf being SHA-256 of the relative
+/// path, a NUL and the file's own hash. Names count as much as contents.
+fn tree_digest<'a>(files: impl Iterator<Item = Result<(&'a Path, [u8; 32])>>) -> Result<[u8; 32]> {
+    let mut root = Sha256::new();
+    for file in files {
+        let (rel, hash) = file?;
+        let mut leaf = Sha256::new();
+        leaf.update(rel.as_os_str().as_bytes());
+        leaf.update([0]);
+        leaf.update(hash);
+        root.update(leaf.finalize());
+    }
+    Ok(root.finalize().into())
+}
+
+fn hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}